@@ -1,5 +1,9 @@
 use crate::config::Address;
 use anyhow::Context;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::time::Duration;
 
 impl Address {
     pub fn choose_address(&self, force_ipv4: bool, force_ipv6: bool) -> anyhow::Result<&str> {
@@ -23,3 +27,29 @@ impl Address {
             .context("No addresses configured for this profile")
     }
 }
+
+/// Resolves the host on another thread, because the system resolver has no timeout of its own,
+/// returning `None` if it doesn't finish in time
+pub fn resolve(host: &str, port: u16, timeout: Duration) -> Option<io::Result<Vec<SocketAddr>>> {
+    let (sender, receiver) = mpsc::channel();
+    let host = host.to_string();
+    std::thread::spawn(move || {
+        let addresses = (host.as_str(), port).to_socket_addrs().map(|a| a.collect());
+        let _ = sender.send(addresses);
+    });
+    receiver.recv_timeout(timeout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_literal() {
+        let addresses = resolve("127.0.0.1", 22, Duration::from_secs(5));
+        assert_eq!(
+            addresses.unwrap().unwrap(),
+            [SocketAddr::from(([127, 0, 0, 1], 22))]
+        );
+    }
+}
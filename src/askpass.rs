@@ -0,0 +1,82 @@
+use crate::exit::launch_error;
+use anyhow::{bail, Context};
+use std::process::{Command, Stdio};
+
+/// Set when remotec is being run by ssh as its `SSH_ASKPASS` program
+const ASKPASS_COMMAND: &str = "REMOTEC_ASKPASS_COMMAND";
+
+/// Runs a `password_command` and returns the first line of its output
+pub fn run_password_command(command: &[String]) -> anyhow::Result<String> {
+    let (program, args) = command.split_first().context("Password command is empty")?;
+    let output = Command::new(program)
+        .args(args)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| launch_error(e, program))?;
+    if !output.status.success() {
+        bail!("Password command failed with {}", output.status);
+    }
+    let output = String::from_utf8(output.stdout).context("Password isn't valid UTF-8")?;
+    Ok(output.lines().next().unwrap_or_default().to_string())
+}
+
+/// Environment that makes ssh ask remotec for passwords, which then runs `command` to get them
+pub fn askpass_env(command: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    let exe = std::env::current_exe().context("Unable to get path to remotec")?;
+    Ok(vec![
+        ("SSH_ASKPASS".to_string(), exe.display().to_string()),
+        ("SSH_ASKPASS_REQUIRE".to_string(), "force".to_string()),
+        (
+            ASKPASS_COMMAND.to_string(),
+            serde_json::to_string(command).unwrap(),
+        ),
+    ])
+}
+
+/// If remotec has been run as an askpass program, answers the prompt and returns the exit code
+pub fn askpass_main() -> Option<i32> {
+    let command = std::env::var(ASKPASS_COMMAND).ok()?;
+    let prompt = std::env::args().nth(1).unwrap_or_default();
+    match answer(&command, &prompt) {
+        Ok(answer) => {
+            println!("{answer}");
+            Some(0)
+        }
+        Err(e) => {
+            eprintln!("remotec: {:#}", e);
+            Some(1)
+        }
+    }
+}
+
+fn answer(command: &str, prompt: &str) -> anyhow::Result<String> {
+    let lower = prompt.to_lowercase();
+    if lower.contains("password") || lower.contains("passphrase") {
+        let command: Vec<String> =
+            serde_json::from_str(command).context("Invalid askpass command")?;
+        return run_password_command(&command);
+    }
+    // Anything else (e.g. confirming a new host key) is for the user to answer
+    ask_terminal(prompt)
+}
+
+#[cfg(unix)]
+fn ask_terminal(prompt: &str) -> anyhow::Result<String> {
+    use std::io::{BufRead, BufReader, Write};
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("Unable to open terminal")?;
+    write!(tty, "{prompt}").context("Unable to write to terminal")?;
+    let mut line = String::new();
+    BufReader::new(tty)
+        .read_line(&mut line)
+        .context("Unable to read from terminal")?;
+    Ok(line.trim_end().to_string())
+}
+
+#[cfg(not(unix))]
+fn ask_terminal(prompt: &str) -> anyhow::Result<String> {
+    bail!("Unable to answer prompt: {}", prompt.trim())
+}
@@ -0,0 +1,479 @@
+use crate::config::{SshForwardArgument, TunnelProfile};
+use crate::exit::{launch_error, status_code};
+use crate::relay::Traffic;
+use crate::runtime::{file_name, runtime_subdirectory, write_atomic};
+use crate::{Config, TunnelAction};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Set for the detached remotec process that runs a background tunnel
+const BACKGROUND_ENV: &str = "REMOTEC_BACKGROUND";
+/// How long to wait for a background tunnel to come up before leaving it to it
+const BACKGROUND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A running tunnel, as recorded in the runtime directory
+#[derive(Deserialize, Serialize)]
+pub struct TunnelState {
+    /// The remotec process running the tunnel (which is also its process group if detached)
+    pub pid: u32,
+    /// When the process started, as reported by the OS, so that another process that has been
+    /// given the same PID isn't mistaken for the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_start: Option<String>,
+    pub profile: String,
+    /// The forwards, with their local ports chosen
+    pub forwards: Vec<SshForwardArgument>,
+    /// Seconds since the Unix epoch
+    pub started: u64,
+    pub background: bool,
+    /// Whether all the forwards have come up
+    pub ready: bool,
+    /// Traffic through each forward, if the tunnel is relayed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub traffic: Vec<Traffic>,
+    /// URL of the PAC file for the tunnel's SOCKS forward
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pac_url: Option<String>,
+}
+
+impl TunnelState {
+    fn uptime(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.saturating_sub(Duration::from_secs(self.started))
+    }
+}
+
+fn state_path(profile: &str) -> anyhow::Result<PathBuf> {
+    Ok(runtime_subdirectory("tunnels")?.join(format!("{}.json", file_name(profile))))
+}
+
+fn log_path(profile: &str) -> anyhow::Result<PathBuf> {
+    Ok(runtime_subdirectory("tunnels")?.join(format!("{}.log", file_name(profile))))
+}
+
+/// Holds a lock on a profile's state until dropped, so that the tunnel's process and remotec
+/// commands don't overwrite each other's changes
+fn lock_state(profile: &str) -> anyhow::Result<File> {
+    let path = runtime_subdirectory("tunnels")?.join(format!("{}.lock", file_name(profile)));
+    let file = File::create(path).context("Unable to create tunnel lock file")?;
+    file.lock().context("Unable to lock tunnel state")?;
+    Ok(file)
+}
+
+fn read_state(path: &PathBuf) -> anyhow::Result<TunnelState> {
+    let contents = fs::read_to_string(path).context("Unable to read tunnel state")?;
+    serde_json::from_str(&contents).context("Unable to parse tunnel state")
+}
+
+/// The state of a profile's tunnel, if it's still running.
+///
+/// State left behind by a process that has exited is removed.
+pub fn running_state(profile: &str) -> anyhow::Result<Option<TunnelState>> {
+    let _lock = lock_state(profile)?;
+    running_state_locked(profile)
+}
+
+fn running_state_locked(profile: &str) -> anyhow::Result<Option<TunnelState>> {
+    let path = state_path(profile)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state = read_state(&path)?;
+    if is_running(state.pid)
+        && (state.process_start.is_none() || state.process_start == process_start(state.pid))
+    {
+        return Ok(Some(state));
+    }
+    log::warn!(
+        "Removing stale state for tunnel `{}` (process {} is no longer running)",
+        state.profile,
+        state.pid
+    );
+    fs::remove_file(&path).context("Unable to remove tunnel state")?;
+    Ok(None)
+}
+
+/// Runs remotec again (with the same arguments, minus `--background`) as a detached process,
+/// and waits for the tunnel to come up
+pub fn start_background(profile: &TunnelProfile) -> anyhow::Result<i32> {
+    if let Some(state) = running_state(&profile.name)? {
+        bail!(
+            "Tunnel `{}` is already running (pid {})",
+            profile.name,
+            state.pid
+        );
+    }
+    let exe = std::env::current_exe().context("Unable to get path of remotec")?;
+    let log = log_path(&profile.name)?;
+    let log_file = File::create(&log).context("Unable to create tunnel log file")?;
+
+    let mut command = Command::new(&exe);
+    command
+        .args(std::env::args().skip(1).filter(|a| a != "--background"))
+        .env(BACKGROUND_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log_file);
+    detach(&mut command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| launch_error(e, &exe.display().to_string()))?;
+
+    let path = state_path(&profile.name)?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().context("Error waiting for tunnel")? {
+            bail!(
+                "Tunnel `{}` exited ({}), see {}",
+                profile.name,
+                status_code(status),
+                log.display()
+            );
+        }
+        let state = read_state(&path).ok().filter(|s| s.pid == child.id());
+        if let Some(state) = state.filter(|s| s.ready) {
+            log::info!(
+                "Started tunnel `{}` in the background (pid {}), logging to {}",
+                profile.name,
+                state.pid,
+                log.display()
+            );
+            return Ok(0);
+        }
+        if start.elapsed() > BACKGROUND_TIMEOUT {
+            log::warn!(
+                "Tunnel `{}` (pid {}) isn't ready yet, see {}",
+                profile.name,
+                child.id(),
+                log.display()
+            );
+            return Ok(0);
+        }
+        sleep(Duration::from_millis(200));
+    }
+}
+
+/// Records the tunnel run by this process, failing if the profile is already running
+pub fn register(profile: &TunnelProfile, forwards: &[SshForwardArgument]) -> anyhow::Result<()> {
+    let _lock = lock_state(&profile.name)?;
+    if let Some(state) = running_state_locked(&profile.name)? {
+        if state.pid != std::process::id() {
+            bail!(
+                "Tunnel `{}` is already running (pid {})",
+                profile.name,
+                state.pid
+            );
+        }
+    }
+    write_state(&TunnelState {
+        pid: std::process::id(),
+        process_start: process_start(std::process::id()),
+        profile: profile.name.clone(),
+        forwards: forwards.to_vec(),
+        started: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        background: std::env::var_os(BACKGROUND_ENV).is_some(),
+        ready: false,
+        traffic: Vec::new(),
+        pac_url: None,
+    })
+}
+
+/// Records that the tunnel run by this process has come up
+pub fn mark_ready(profile: &TunnelProfile) -> anyhow::Result<()> {
+    update_state(&profile.name, |state| state.ready = true)
+}
+
+/// Records the traffic through the relayed tunnel run by this process
+pub fn record_traffic(profile: &str, traffic: Vec<Traffic>) -> anyhow::Result<()> {
+    update_state(profile, |state| state.traffic = traffic)
+}
+
+/// Records the URL of the PAC file served for the tunnel run by this process
+pub fn record_pac(profile: &TunnelProfile, url: &str) -> anyhow::Result<()> {
+    update_state(&profile.name, |state| state.pac_url = Some(url.to_string()))
+}
+
+/// Changes the state of the tunnel run by this process
+fn update_state(profile: &str, update: impl FnOnce(&mut TunnelState)) -> anyhow::Result<()> {
+    let _lock = lock_state(profile)?;
+    let mut state = read_state(&state_path(profile)?)?;
+    if state.pid == std::process::id() {
+        update(&mut state);
+        write_state(&state)?;
+    }
+    Ok(())
+}
+
+/// Removes the record of the tunnel run by this process once it exits
+pub fn unregister(profile: &TunnelProfile) {
+    let (Ok(_lock), Ok(path)) = (lock_state(&profile.name), state_path(&profile.name)) else {
+        return;
+    };
+    if let Ok(state) = read_state(&path) {
+        if state.pid == std::process::id() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn write_state(state: &TunnelState) -> anyhow::Result<()> {
+    write_atomic(
+        &state_path(&state.profile)?,
+        serde_json::to_string_pretty(state).unwrap(),
+    )
+    .context("Unable to write tunnel state")
+}
+
+pub fn launch_tunnel_action(config: &Config, action: &TunnelAction) -> anyhow::Result<i32> {
+    match action {
+        TunnelAction::List => list_tunnels(config),
+        TunnelAction::Ports { name } => match running_state(name)? {
+            Some(state) => {
+                for f in &state.forwards {
+                    if let Some(port) = f.forward.local_port() {
+                        println!("{port}\t{}", f.description());
+                    }
+                }
+                if let Some(url) = &state.pac_url {
+                    println!("{}\tPAC file {url}", url_port(url));
+                }
+                Ok(0)
+            }
+            None => {
+                log::warn!("Tunnel `{name}` isn't running");
+                Ok(1)
+            }
+        },
+        TunnelAction::Stop { name, all } => {
+            let names = if *all {
+                all_states()?
+                    .into_iter()
+                    .filter(|s| s.background)
+                    .map(|s| s.profile)
+                    .collect()
+            } else {
+                vec![name.clone().unwrap()]
+            };
+            let mut code = 0;
+            for name in names {
+                let _lock = lock_state(&name)?;
+                match running_state_locked(&name)? {
+                    Some(state) if !state.background => {
+                        log::warn!(
+                            "Tunnel `{name}` is running in the foreground (pid {}), \
+                            stop it from its terminal",
+                            state.pid
+                        );
+                        code = 1;
+                    }
+                    Some(state) => {
+                        terminate(state.pid)?;
+                        fs::remove_file(state_path(&name)?)
+                            .context("Unable to remove tunnel state")?;
+                        log::info!("Stopped tunnel `{}` (pid {})", name, state.pid);
+                    }
+                    None => {
+                        log::warn!("Tunnel `{name}` isn't running");
+                        code = 1;
+                    }
+                }
+            }
+            Ok(code)
+        }
+    }
+}
+
+/// Every background tunnel that is still running
+fn all_states() -> anyhow::Result<Vec<TunnelState>> {
+    let dir = runtime_subdirectory("tunnels")?;
+    let mut states = Vec::new();
+    for entry in fs::read_dir(dir).context("Unable to read tunnels directory")? {
+        let path = entry.context("Unable to read tunnels directory")?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let profile = read_state(&path)?.profile;
+        states.extend(running_state(&profile)?);
+    }
+    states.sort_by(|a, b| a.profile.cmp(&b.profile));
+    Ok(states)
+}
+
+fn list_tunnels(config: &Config) -> anyhow::Result<i32> {
+    for state in all_states()? {
+        if !config.tunnels.iter().any(|t| t.name == state.profile) {
+            log::warn!("Tunnel `{}` is no longer in the config", state.profile);
+        }
+        let mode = if state.background {
+            "background"
+        } else {
+            "foreground"
+        };
+        let forwards = state
+            .forwards
+            .iter()
+            .enumerate()
+            .map(|(i, f)| match state.traffic.get(i) {
+                Some(t) if f.forward.local_port().is_some() => format!(
+                    "{} ({} connections, {} active, {} sent, {} received)",
+                    f.description(),
+                    t.connections,
+                    t.active,
+                    format_bytes(t.sent),
+                    format_bytes(t.received)
+                ),
+                _ => f.description(),
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            state.profile,
+            state.pid,
+            mode,
+            format_duration(state.uptime()),
+            forwards.join(", ")
+        );
+    }
+    Ok(0)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// The port of a `http://host:port/...` URL
+fn url_port(url: &str) -> &str {
+    url.rsplit_once(':')
+        .and_then(|(_, rest)| rest.split('/').next())
+        .unwrap_or("-")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        fn detach(command: &mut Command) {
+            use std::os::unix::process::CommandExt;
+            // A new process group isn't interrupted by Ctrl-C in the terminal, and lets the
+            // tunnel be stopped along with its ssh child
+            command.process_group(0);
+        }
+
+        /// The process's start time in clock ticks since boot (field 22 of `/proc/<pid>/stat`)
+        #[cfg(target_os = "linux")]
+        fn process_start(pid: u32) -> Option<String> {
+            let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+            // The command name (field 2) is in parentheses and may contain spaces
+            let fields = stat.rsplit_once(')')?.1;
+            fields.split_whitespace().nth(19).map(str::to_string)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn process_start(pid: u32) -> Option<String> {
+            let output = Command::new("ps")
+                .args(["-o", "lstart=", "-p", &pid.to_string()])
+                .output()
+                .ok()?;
+            let start = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            (output.status.success() && !start.is_empty()).then_some(start)
+        }
+
+        fn is_running(pid: u32) -> bool {
+            Command::new("kill")
+                .args(["-0", &pid.to_string()])
+                .stderr(Stdio::null())
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false)
+        }
+
+        fn terminate(pid: u32) -> anyhow::Result<()> {
+            let status = Command::new("kill")
+                .args(["-TERM", "--", &format!("-{pid}")])
+                .status()
+                .map_err(|e| launch_error(e, "kill"))?;
+            if !status.success() {
+                bail!("Unable to stop process {pid}");
+            }
+            Ok(())
+        }
+    } else if #[cfg(windows)] {
+        fn detach(command: &mut Command) {
+            use std::os::windows::process::CommandExt;
+            const DETACHED_PROCESS: u32 = 0x00000008;
+            const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+            command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+        }
+
+        /// Getting a process's start time needs the Windows API, so reused PIDs aren't detected
+        fn process_start(_pid: u32) -> Option<String> {
+            None
+        }
+
+        fn is_running(pid: u32) -> bool {
+            Command::new("tasklist")
+                .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+                .output()
+                .map(|o| String::from_utf8_lossy(&o.stdout).contains(&pid.to_string()))
+                .unwrap_or(false)
+        }
+
+        fn terminate(pid: u32) -> anyhow::Result<()> {
+            let status = Command::new("taskkill")
+                .args(["/T", "/F", "/PID", &pid.to_string()])
+                .status()
+                .map_err(|e| launch_error(e, "taskkill"))?;
+            if !status.success() {
+                bail!("Unable to stop process {pid}");
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn process_start_identifies_process() {
+        let start = process_start(std::process::id());
+        assert!(start.is_some());
+        assert_eq!(start, process_start(std::process::id()));
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert_eq!(process_start(pid), None);
+    }
+}
@@ -1,17 +1,477 @@
+use crate::config::{CommandParameter, CommandProfile, SshBackend};
+use crate::exit::{launch_error, status_code, Failure};
 use crate::select::select_profile_by_name;
-use crate::ssh::{invoke_ssh, ssh_args};
+use crate::ssh::{invoke_ssh, ssh_command, ssh_target};
 use crate::{Command, Config};
-use anyhow::bail;
+use anyhow::{bail, Context};
+use glob::Pattern;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::Stdio;
+use std::sync::Mutex;
 
-pub fn launch_command(config: &Config, cli: &Command) -> anyhow::Result<()> {
+pub fn launch_command(config: &Config, cli: &Command) -> anyhow::Result<i32> {
     let profile = select_profile_by_name("Command", &config.commands, &cli.name, true)?;
     if profile.command.is_empty() {
         bail!("Profile doesn't contain any command");
     }
-    let mut ssh_args = ssh_args(config, &cli.common, &profile.ssh_profile, false)?;
+    let command = expand_command(profile, cli)?;
+    let selectors = if cli.on.is_empty() {
+        &profile.ssh_profile
+    } else {
+        &cli.on
+    };
+    // A single named host runs the command directly, as if it was run with `remotec ssh`
+    if let [name] = selectors.as_slice() {
+        if !is_pattern(name) {
+            let target = ssh_target(config, &cli.common, name, false)?;
+            return invoke_ssh(target, command, cli.common.stdout);
+        }
+    }
+    let hosts = select_hosts(config, selectors)?;
+    if cli.common.stdout {
+        for host in hosts {
+            let target = ssh_target(config, &cli.common, host, false)?;
+            invoke_ssh(target, command.clone(), true)?;
+        }
+        return Ok(0);
+    }
+    fan_out(config, cli, &command, &hosts)
+}
 
-    ssh_args.append(&mut profile.command.clone());
+/// The profile's command, with the parameters' placeholders replaced by their (shell quoted)
+/// values
+fn expand_command(profile: &CommandProfile, cli: &Command) -> anyhow::Result<Vec<String>> {
+    let parameters = &profile.parameters;
+    if parameters.is_empty() && (!cli.args.is_empty() || !cli.set.is_empty()) {
+        bail!("Command `{}` doesn't take any parameters", profile.name);
+    }
+    if cli.args.len() > parameters.len() {
+        bail!(
+            "Too many arguments, command `{}` takes {}",
+            profile.name,
+            parameters.len()
+        );
+    }
+    let mut given = cli
+        .args
+        .iter()
+        .map(String::as_str)
+        .map(Some)
+        .collect::<Vec<_>>();
+    given.resize(parameters.len(), None);
+    for set in &cli.set {
+        let (name, value) = set
+            .split_once('=')
+            .with_context(|| format!("Expected `name=value`, not `{set}`"))?;
+        let index = parameters
+            .iter()
+            .position(|p| p.name == name)
+            .with_context(|| format!("Command `{}` has no parameter `{name}`", profile.name))?;
+        given[index] = Some(value);
+    }
 
-    invoke_ssh(ssh_args, cli.common.stdout)?;
+    let mut values = BTreeMap::new();
+    for (parameter, given) in parameters.iter().zip(given) {
+        let required = parameter.required.unwrap_or(parameter.default.is_none());
+        let value = match (given, &parameter.default) {
+            (Some(value), _) => value,
+            (None, _) if required => bail!(
+                "Missing a value for `{}`{}",
+                parameter.name,
+                parameter
+                    .description
+                    .as_ref()
+                    .map(|d| format!(" ({d})"))
+                    .unwrap_or_default()
+            ),
+            (None, Some(default)) => default.as_str(),
+            (None, None) => "",
+        };
+        check_value(parameter, value)?;
+        values.insert(
+            parameter.name.as_str(),
+            shell_words::quote(value).into_owned(),
+        );
+    }
+    Ok(profile
+        .command
+        .iter()
+        .map(|word| fill_placeholders(word, &values))
+        .collect())
+}
+
+fn check_value(parameter: &CommandParameter, value: &str) -> anyhow::Result<()> {
+    if !parameter.allowed.is_empty() && !parameter.allowed.iter().any(|a| a == value) {
+        bail!(
+            "`{value}` isn't allowed for `{}`, expected one of: {}",
+            parameter.name,
+            parameter.allowed.join(", ")
+        );
+    }
+    if let Some(pattern) = &parameter.pattern {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .with_context(|| format!("Invalid pattern for `{}`", parameter.name))?;
+        if !regex.is_match(value) {
+            bail!(
+                "`{value}` doesn't match the pattern for `{}`: {pattern}",
+                parameter.name
+            );
+        }
+    }
     Ok(())
 }
+
+/// Replaces the `{name}` placeholders of the parameters, leaving any other braces as they are
+fn fill_placeholders(word: &str, values: &BTreeMap<&str, String>) -> String {
+    let mut filled = String::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((end, values.get(&rest[1..end])?)));
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn is_pattern(selector: &str) -> bool {
+    selector.starts_with("tag:") || selector.contains(['*', '?', '['])
+}
+
+/// The names of the SSH profiles matched by the selectors, in config order
+fn select_hosts<'a>(config: &'a Config, selectors: &[String]) -> anyhow::Result<Vec<&'a str>> {
+    let mut matched = vec![false; config.ssh.len()];
+    for selector in selectors {
+        let matches = |name: &str, tags: &[String]| -> anyhow::Result<bool> {
+            Ok(match selector.strip_prefix("tag:") {
+                Some(tag) => tags.iter().any(|t| t == tag),
+                None => {
+                    name == selector
+                        || Pattern::new(selector)
+                            .with_context(|| format!("Invalid profile pattern `{selector}`"))?
+                            .matches(name)
+                }
+            })
+        };
+        let mut any = false;
+        for (i, profile) in config.ssh.iter().enumerate() {
+            if matches(&profile.name, &profile.tags)? {
+                matched[i] = true;
+                any = true;
+            }
+        }
+        if !any {
+            return Err(anyhow::anyhow!("No SSH profiles match `{selector}`"))
+                .context(Failure::ProfileNotFound);
+        }
+    }
+    Ok(config
+        .ssh
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| *matched)
+        .map(|(profile, _)| profile.name.as_str())
+        .collect())
+}
+
+/// Runs the command on each of the hosts, printing their output with the host's name, followed
+/// by a summary of their exit codes
+fn fan_out(
+    config: &Config,
+    cli: &Command,
+    command: &[String],
+    hosts: &[&str],
+) -> anyhow::Result<i32> {
+    let parallel = cli.parallel.unwrap_or(hosts.len());
+    if parallel == 0 {
+        bail!("--parallel must be at least 1");
+    }
+    let width = hosts.iter().map(|h| h.len()).max().unwrap_or(0);
+    let next = Mutex::new(0);
+    let results = Mutex::new(
+        hosts
+            .iter()
+            .map(|_| None)
+            .collect::<Vec<Option<anyhow::Result<i32>>>>(),
+    );
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.min(hosts.len()) {
+            scope.spawn(|| loop {
+                let i = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                let Some(host) = hosts.get(i) else {
+                    return;
+                };
+                let prefix = format!("{host:width$} | ");
+                let result = run_on_host(config, cli, command, host, &prefix);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    let mut failed = false;
+    for (host, result) in hosts.iter().zip(results.into_inner().unwrap()) {
+        let status = match result.unwrap() {
+            Ok(0) => "0".to_string(),
+            Ok(code) => {
+                failed = true;
+                code.to_string()
+            }
+            Err(e) => {
+                failed = true;
+                format!("failed: {e:#}")
+            }
+        };
+        eprintln!("{host:width$}  {status}");
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+fn run_on_host(
+    config: &Config,
+    cli: &Command,
+    command: &[String],
+    host: &str,
+    prefix: &str,
+) -> anyhow::Result<i32> {
+    let mut target = ssh_target(config, &cli.common, host, false)?;
+    if let SshBackend::Native = target.backend {
+        bail!("Running a command on several hosts needs the OpenSSH backend");
+    }
+    target.preflight()?;
+    // The hosts can't share the terminal's input
+    target.options.push("-n".to_string());
+    let mut child = ssh_command(target, command.to_vec())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| launch_error(e, "ssh"))?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    std::thread::scope(|scope| {
+        scope.spawn(|| prefix_lines(stdout, std::io::stdout(), prefix));
+        prefix_lines(stderr, std::io::stderr(), prefix);
+    });
+    let status = child.wait().context("Error waiting for ssh")?;
+    Ok(status_code(status))
+}
+
+/// Copies the output a line at a time, starting each with the prefix
+fn prefix_lines(from: impl Read, mut to: impl Write, prefix: &str) {
+    let mut from = BufReader::new(from);
+    let mut line = prefix.as_bytes().to_vec();
+    loop {
+        line.truncate(prefix.len());
+        match from.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                let _ = to.write_all(&line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts_config() -> Config {
+        let config = serde_json::json!({"ssh": [
+            {"name": "web-1", "hostname": "10.0.0.1", "tags": ["web", "prod"]},
+            {"name": "web-2", "hostname": "10.0.0.2", "tags": ["web"]},
+            {"name": "db-1", "hostname": "10.0.0.3", "tags": ["prod"]},
+        ]});
+        Config::parse(&config.to_string()).unwrap()
+    }
+
+    fn select(selectors: &[&str]) -> anyhow::Result<Vec<String>> {
+        let config = hosts_config();
+        let selectors = selectors.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let hosts = select_hosts(&config, &selectors)?;
+        Ok(hosts.into_iter().map(String::from).collect())
+    }
+
+    #[test]
+    fn select_by_name_glob_and_tag() {
+        assert_eq!(select(&["db-1"]).unwrap(), ["db-1"]);
+        assert_eq!(select(&["web-*"]).unwrap(), ["web-1", "web-2"]);
+        assert_eq!(select(&["tag:prod"]).unwrap(), ["web-1", "db-1"]);
+        // Hosts are listed once, in the config's order
+        assert_eq!(
+            select(&["db-1", "tag:web", "web-1"]).unwrap(),
+            ["web-1", "web-2", "db-1"]
+        );
+    }
+
+    #[test]
+    fn select_without_match() {
+        for selector in ["cache-1", "cache-*", "tag:staging"] {
+            let error = select(&["web-1", selector]).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(Failure::ProfileNotFound)
+            ));
+            let message = format!("No SSH profiles match `{selector}`");
+            assert!(format!("{error:#}").contains(&message), "{error:#}");
+        }
+        assert!(select(&["web-["]).is_err());
+    }
+
+    #[test]
+    fn prefixed_lines() {
+        let mut output = Vec::new();
+        prefix_lines(&b"one\ntwo\n\nlast"[..], &mut output, "[web-1] ");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[web-1] one\n[web-1] two\n[web-1] \n[web-1] last\n"
+        );
+
+        let mut output = Vec::new();
+        prefix_lines(&b""[..], &mut output, "[web-1] ");
+        assert!(output.is_empty());
+    }
+
+    fn restart_profile() -> CommandProfile {
+        serde_json::from_value(serde_json::json!({
+            "name": "restart",
+            "ssh_profile": "web-1",
+            "command": ["sudo", "systemctl", "{action}", "{service}", "--wait={timeout}s"],
+            "parameters": [
+                {"name": "service", "pattern": "[a-z0-9@._-]+"},
+                {"name": "action", "allowed": ["restart", "reload"], "default": "restart"},
+                {"name": "timeout", "default": "30", "pattern": "[0-9]+"},
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn expand(args: &[&str], set: &[&str]) -> anyhow::Result<Vec<String>> {
+        let cli = Command {
+            name: "restart".to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            set: set.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        expand_command(&restart_profile(), &cli)
+    }
+
+    #[test]
+    fn positional_and_set_values() {
+        assert_eq!(
+            expand(&["nginx"], &[]).unwrap(),
+            ["sudo", "systemctl", "restart", "nginx", "--wait=30s"]
+        );
+        assert_eq!(
+            expand(&["nginx", "reload"], &["timeout=5"]).unwrap(),
+            ["sudo", "systemctl", "reload", "nginx", "--wait=5s"]
+        );
+        // --set takes precedence over a positional value
+        assert_eq!(
+            expand(&["nginx", "restart"], &["action=reload", "service=app"]).unwrap(),
+            ["sudo", "systemctl", "reload", "app", "--wait=30s"]
+        );
+        assert!(expand(&["nginx", "reload", "5", "extra"], &[]).is_err());
+        assert!(expand(&["nginx"], &["user=root"]).is_err());
+        assert!(expand(&["nginx"], &["timeout"]).is_err());
+    }
+
+    #[test]
+    fn required_and_default_values() {
+        let error = expand(&[], &[]).unwrap_err();
+        assert_eq!(error.to_string(), "Missing a value for `service`");
+
+        let mut profile = restart_profile();
+        profile.parameters[1].required = Some(true);
+        profile.parameters.push(
+            serde_json::from_value(serde_json::json!({"name": "unused", "required": false}))
+                .unwrap(),
+        );
+        let cli = Command {
+            args: vec!["nginx".to_string()],
+            ..Default::default()
+        };
+        let error = expand_command(&profile, &cli).unwrap_err();
+        assert_eq!(error.to_string(), "Missing a value for `action`");
+        let cli = Command {
+            args: vec!["nginx".to_string(), "reload".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            expand_command(&profile, &cli).unwrap(),
+            ["sudo", "systemctl", "reload", "nginx", "--wait=30s"]
+        );
+    }
+
+    #[test]
+    fn rejected_values() {
+        let error = expand(&["nginx", "stop"], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`stop` isn't allowed for `action`, expected one of: restart, reload"
+        );
+        // The pattern has to match the whole value
+        let error = expand(&["nginx; rm -rf /"], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`nginx; rm -rf /` doesn't match the pattern for `service`: [a-z0-9@._-]+"
+        );
+        assert!(expand(&["nginx"], &["timeout=5x"]).is_err());
+    }
+
+    #[test]
+    fn hostile_values_are_quoted() {
+        let mut profile = restart_profile();
+        profile.parameters[0].pattern = None;
+        for value in ["$(reboot)", "a b", "it's", "`id`", "x; rm -rf /", ""] {
+            let cli = Command {
+                args: vec![value.to_string()],
+                ..Default::default()
+            };
+            let command = expand_command(&profile, &cli).unwrap();
+            assert_eq!(command[3], shell_words::quote(value));
+            // The remote shell sees the value as a single word
+            assert_eq!(shell_words::split(&command[3]).unwrap(), [value]);
+        }
+    }
+
+    #[test]
+    fn unknown_braces_are_kept() {
+        let values = BTreeMap::from([("name", "web".to_string())]);
+        assert_eq!(fill_placeholders("{name}-{id}", &values), "web-{id}");
+        assert_eq!(fill_placeholders("{{name}}", &values), "{web}");
+        assert_eq!(
+            fill_placeholders("awk '{print $1}'", &values),
+            "awk '{print $1}'"
+        );
+        assert_eq!(fill_placeholders("{name", &values), "{name");
+        assert_eq!(fill_placeholders("}{}", &values), "}{}");
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let parameter: CommandParameter =
+            serde_json::from_value(serde_json::json!({"name": "x", "pattern": "("})).unwrap();
+        let error = check_value(&parameter, "a").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid pattern for `x`");
+    }
+}
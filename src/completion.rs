@@ -1,6 +1,8 @@
 // inspired by: https://www.joshmcguigan.com/blog/shell-completions-pure-rust/
 // this is my first ever attempt at a completion script - it's probably not very good!
 
+// The completion script only needs the profile names
+#[allow(dead_code)]
 mod config;
 
 use crate::config::Config;
@@ -85,23 +87,23 @@ fn main() {
         current_idx: 1,
     };
 
-    let subcommands = vec!["rdp", "ssh", "tunnel", "command", "config"];
+    let subcommands = vec![
+        "rdp", "ssh", "tunnel", "command", "mux", "hostkeys", "ping", "config",
+    ];
     match ctx.next_arg() {
         None => {
             ctx.input.complete_subcommand(subcommands);
         }
-        Some(arg) => {
-            if ctx.new_arg() {
-                match arg.as_str() {
-                    "rdp" => complete_rdp(ctx),
-                    "ssh" => complete_ssh(ctx),
-                    "tunnel" => complete_tunnel(ctx),
-                    "command" => complete_command(ctx),
-                    _ => {}
-                }
-            } else {
-                ctx.input.complete_subcommand(subcommands);
-            }
+        Some(arg) if ctx.new_arg() => match arg.as_str() {
+            "rdp" => complete_rdp(ctx),
+            "ssh" => complete_ssh(ctx),
+            "tunnel" => complete_tunnel(ctx),
+            "command" => complete_command(ctx),
+            "mux" => complete_mux(ctx),
+            _ => {}
+        },
+        Some(_) => {
+            ctx.input.complete_subcommand(subcommands);
         }
     }
 }
@@ -118,17 +120,16 @@ fn complete_rdp(mut ctx: Context) {
         None => {
             ctx.input.complete_subcommand(possibilities);
         }
-        Some(_arg) => {
-            if ctx.new_arg() {
-                let filtered = ctx.filter_existing_options(RDP_OPTIONS);
-                let options = filtered
-                    .iter()
-                    .flat_map(|c| c.suggestion())
-                    .collect::<Vec<_>>();
-                ctx.input.complete_subcommand(options);
-            } else {
-                ctx.input.complete_subcommand(possibilities);
-            }
+        Some(_) if ctx.new_arg() => {
+            let filtered = ctx.filter_existing_options(RDP_OPTIONS);
+            let options = filtered
+                .iter()
+                .flat_map(|c| c.suggestion())
+                .collect::<Vec<_>>();
+            ctx.input.complete_subcommand(options);
+        }
+        Some(_) => {
+            ctx.input.complete_subcommand(possibilities);
         }
     }
 }
@@ -145,17 +146,16 @@ fn complete_ssh(mut ctx: Context) {
         None => {
             ctx.input.complete_subcommand(possibilities);
         }
-        Some(_arg) => {
-            if ctx.new_arg() {
-                let filtered = ctx.filter_existing_options(SSH_OPTIONS);
-                let options = filtered
-                    .iter()
-                    .flat_map(|c| c.suggestion())
-                    .collect::<Vec<_>>();
-                ctx.input.complete_subcommand(options);
-            } else {
-                ctx.input.complete_subcommand(possibilities);
-            }
+        Some(_) if ctx.new_arg() => {
+            let filtered = ctx.filter_existing_options(SSH_SESSION_OPTIONS);
+            let options = filtered
+                .iter()
+                .flat_map(|c| c.suggestion())
+                .collect::<Vec<_>>();
+            ctx.input.complete_subcommand(options);
+        }
+        Some(_) => {
+            ctx.input.complete_subcommand(possibilities);
         }
     }
 }
@@ -166,23 +166,41 @@ fn complete_tunnel(mut ctx: Context) {
         .config
         .tunnels
         .iter()
-        .map(|r| r.name.as_str())
+        .map(|r| r.name.clone())
+        .chain(
+            ctx.config
+                .tunnel_groups
+                .iter()
+                .map(|g| format!("@{}", g.name)),
+        )
+        .chain(["list", "ports", "stop"].map(String::from))
         .collect::<Vec<_>>();
+    let possibilities = possibilities.iter().map(String::as_str).collect::<Vec<_>>();
     match next {
         None => {
             ctx.input.complete_subcommand(possibilities);
         }
-        Some(_arg) => {
-            if ctx.new_arg() {
-                let filtered = ctx.filter_existing_options(SSH_OPTIONS);
-                let options = filtered
-                    .iter()
-                    .flat_map(|c| c.suggestion())
-                    .collect::<Vec<_>>();
-                ctx.input.complete_subcommand(options);
-            } else {
-                ctx.input.complete_subcommand(possibilities);
-            }
+        Some(arg) if (arg == "stop" || arg == "ports") && ctx.new_arg() => {
+            let names = ctx
+                .config
+                .tunnels
+                .iter()
+                .map(|r| r.name.as_str())
+                .chain((arg == "stop").then_some("--all"))
+                .collect::<Vec<_>>();
+            ctx.input.complete_subcommand(names);
+        }
+        Some(arg) if arg == "list" => {}
+        Some(_) if ctx.new_arg() => {
+            let filtered = ctx.filter_existing_options(TUNNEL_OPTIONS);
+            let options = filtered
+                .iter()
+                .flat_map(|c| c.suggestion())
+                .collect::<Vec<_>>();
+            ctx.input.complete_subcommand(options);
+        }
+        Some(_) => {
+            ctx.input.complete_subcommand(possibilities);
         }
     }
 }
@@ -199,18 +217,42 @@ fn complete_command(mut ctx: Context) {
         None => {
             ctx.input.complete_subcommand(possibilities);
         }
-        Some(_arg) => {
-            if ctx.new_arg() {
-                let filtered = ctx.filter_existing_options(SSH_OPTIONS);
-                let options = filtered
-                    .iter()
-                    .flat_map(|c| c.suggestion())
-                    .collect::<Vec<_>>();
-                ctx.input.complete_subcommand(options);
-            } else {
+        Some(_) if ctx.new_arg() => {
+            let filtered = ctx.filter_existing_options(COMMAND_OPTIONS);
+            let options = filtered
+                .iter()
+                .flat_map(|c| c.suggestion())
+                .collect::<Vec<_>>();
+            ctx.input.complete_subcommand(options);
+        }
+        Some(_) => {
+            ctx.input.complete_subcommand(possibilities);
+        }
+    }
+}
+
+fn complete_mux(mut ctx: Context) {
+    let actions = vec!["status", "stop"];
+    match ctx.next_arg() {
+        None => {
+            ctx.input.complete_subcommand(actions);
+        }
+        Some(_) if ctx.new_arg() => {
+            let next = ctx.next_arg();
+            let possibilities = ctx
+                .config
+                .ssh
+                .iter()
+                .filter(|r| r.multiplex)
+                .map(|r| r.name.as_str())
+                .collect::<Vec<_>>();
+            if next.is_none() || !ctx.new_arg() {
                 ctx.input.complete_subcommand(possibilities);
             }
         }
+        Some(_) => {
+            ctx.input.complete_subcommand(actions);
+        }
     }
 }
 
@@ -243,11 +285,46 @@ const RDP_OPTIONS: &[CliOption] = &[
     CliOption::new(None, Some("--stdout")),
 ];
 
-const SSH_OPTIONS: &[CliOption] = &[
-    CliOption::new(Some("-d"), Some("--disable-jumphosts")),
+const COMMAND_OPTIONS: &[CliOption] = &[
+    CliOption::new(Some("-d"), Some("--disable-jump-hosts")),
+    CliOption::new(None, Some("--help")),
+    CliOption::new(None, Some("--ipv4")),
+    CliOption::new(None, Some("--ipv6")),
+    CliOption::new(None, Some("--on")),
+    CliOption::new(None, Some("--parallel")),
+    CliOption::new(None, Some("--preflight")),
+    CliOption::new(None, Some("--set")),
+    CliOption::new(Some("-j"), Some("--use-jump-hosts")),
+    CliOption::new(None, Some("--stdout")),
+];
+
+const TUNNEL_OPTIONS: &[CliOption] = &[
+    CliOption::new(None, Some("--background")),
+    CliOption::new(Some("-d"), Some("--disable-jump-hosts")),
+    CliOption::new(None, Some("--env")),
+    CliOption::new(None, Some("--exec")),
+    CliOption::new(None, Some("--help")),
+    CliOption::new(None, Some("--install-systemd")),
+    CliOption::new(None, Some("--ipv4")),
+    CliOption::new(None, Some("--ipv6")),
+    CliOption::new(None, Some("--preflight")),
+    CliOption::new(None, Some("--shell")),
+    CliOption::new(None, Some("--socket")),
+    CliOption::new(None, Some("--uninstall-systemd")),
+    CliOption::new(Some("-j"), Some("--use-jump-hosts")),
+    CliOption::new(None, Some("--stdout")),
+];
+
+const SSH_SESSION_OPTIONS: &[CliOption] = &[
+    CliOption::new(Some("-d"), Some("--disable-jump-hosts")),
     CliOption::new(None, Some("--help")),
     CliOption::new(None, Some("--ipv4")),
     CliOption::new(None, Some("--ipv6")),
+    CliOption::new(None, Some("--list-sessions")),
+    CliOption::new(None, Some("--mosh")),
+    CliOption::new(None, Some("--no-session")),
+    CliOption::new(None, Some("--preflight")),
+    CliOption::new(None, Some("--session")),
     CliOption::new(Some("-j"), Some("--use-jump-hosts")),
     CliOption::new(None, Some("--stdout")),
 ];
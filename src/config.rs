@@ -1,5 +1,7 @@
 use anyhow::{bail, Context};
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -13,8 +15,6 @@ struct ConfigFile {
     rdp_defaults: RdpDefaults,
     #[serde(default)]
     ssh_defaults: SshDefaults,
-    #[serde(default)]
-    commands: Vec<CommandProfile>,
 }
 
 #[derive(Deserialize, Serialize, Default)]
@@ -26,6 +26,8 @@ struct SatelliteConfig {
     #[serde(default)]
     tunnels: Vec<TunnelProfile>,
     #[serde(default)]
+    tunnel_groups: Vec<TunnelGroup>,
+    #[serde(default)]
     commands: Vec<CommandProfile>,
 }
 
@@ -38,14 +40,24 @@ pub struct RdpDefaults {
 #[derive(Deserialize, Serialize, Default)]
 pub struct SshDefaults {
     pub username: Option<String>,
+    pub backend: Option<SshBackend>,
 }
 
-/// At present we only support launching the Microsoft Windows Remote Desktop client (mstsc.exe)
-/// But in future we could support Linux clients etc.
+/// By default remotec launches the OpenSSH client, but when built with the `native-ssh` feature
+/// it can instead connect using an in-process client (for systems without OpenSSH)
+#[derive(Deserialize, Serialize, Copy, Clone)]
+pub enum SshBackend {
+    OpenSsh,
+    Native,
+}
+
+/// The Microsoft Windows Remote Desktop client (mstsc.exe) is used by default on Windows,
+/// and FreeRDP (xfreerdp) everywhere else
 #[derive(Deserialize, Serialize, Copy, Clone)]
 pub enum RdpBackend {
     #[cfg(windows)]
     Mstsc,
+    FreeRdp,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -68,6 +80,8 @@ pub struct RdpProfile {
     pub gateway_policy: GatewayPolicy,
     #[serde(default)]
     pub separate_credentials: bool,
+    /// Command that prints the password, e.g. `["pass", "show", "x"]`
+    pub password_command: Option<Vec<String>>,
     pub description: Option<String>,
 }
 
@@ -81,23 +95,390 @@ pub struct SshProfile {
     pub disable_jump_hosts: bool,
     #[serde(default)]
     pub jump_hosts: Vec<SshJumpHost>,
+    /// Share a single master connection between invocations of this profile
+    #[serde(default)]
+    pub multiplex: bool,
+    /// How long an idle master connection is kept open, in `ControlPersist` format
+    pub multiplex_persist: Option<String>,
+    /// Launch interactive sessions using mosh instead of ssh
+    #[serde(default)]
+    pub mosh: bool,
+    /// Path to `mosh-server` on the remote host
+    pub mosh_server: Option<String>,
+    /// UDP port (or `from:to` range) for mosh to use
+    pub mosh_ports: Option<String>,
+    /// A remote tmux/screen session to attach to when launching the profile
+    pub session: Option<SshSession>,
+    /// Directory to change to when launching an interactive session
+    pub remote_dir: Option<String>,
+    /// Environment variables to set for interactive sessions
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Whether to send `env` using ssh's `SetEnv` (which the server must `AcceptEnv`) only
+    /// (`true`), or only export the variables in a wrapper script (`false`). By default both are
+    /// used, so the wrapper sets any variables that the server doesn't accept.
+    pub set_env: Option<bool>,
+    /// Shell command to run at the start of an interactive session
+    pub startup_command: Option<String>,
+    /// Pinned host keys, as public keys (`ssh-ed25519 AAAA...`) or `SHA256:` fingerprints
+    #[serde(default)]
+    pub host_keys: Vec<String>,
+    /// Command that prints the password (or key passphrase) for ssh, e.g. `["pass", "show", "x"]`
+    pub password_command: Option<Vec<String>>,
+    /// Check that the host is reachable and running SSH before connecting
+    #[serde(default)]
+    pub preflight: bool,
+    /// Tags for selecting the profile in a command's `ssh_profile` or `--on` (as `tag:<name>`)
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub description: Option<String>,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct SshSession {
+    #[serde(default)]
+    pub multiplexer: Multiplexer,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Multiplexer {
+    #[default]
+    Tmux,
+    Screen,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct TunnelProfile {
     pub name: String,
-    pub ssh_profile: String,
+    #[serde(default)]
+    pub transport: TunnelTransport,
+    /// The SSH profile to connect to (for the `ssh` transport)
+    pub ssh_profile: Option<String>,
+    /// The kubectl context (for the `kubectl` transport), or the current context if unset
+    pub context: Option<String>,
+    /// The Kubernetes namespace, or the context's default if unset
+    pub namespace: Option<String>,
+    /// The resource to forward to with `kubectl port-forward`, e.g. `svc/grafana` or `pod/x`
+    pub target: Option<String>,
     pub forwards: Vec<SshForwardArgument>,
-    pub open: Option<String>,
+    /// Locations to open once the tunnel is ready, which may refer to the forwards' local ports
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub open: Vec<String>,
+    /// The application to open them with (instead of the default)
+    pub open_with: Option<String>,
+    /// Restart ssh when the connection drops
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Seconds between keepalive messages to the server (`ServerAliveInterval`)
+    pub server_alive_interval: Option<u32>,
+    /// Unanswered keepalive messages before the connection is dropped (`ServerAliveCountMax`)
+    pub server_alive_count_max: Option<u32>,
+    /// Accept connections on the local ports in remotec and relay them through ssh, counting the
+    /// traffic
+    #[serde(default)]
+    pub relay: bool,
+    /// Close the tunnel after this many seconds without any relayed traffic (implies `relay`)
+    pub idle_timeout: Option<u64>,
+    /// Domains to send through the dynamic (SOCKS) forward, in a PAC file that is served while
+    /// the tunnel is open
+    #[serde(default)]
+    pub proxy_domains: Vec<String>,
+    /// Local port to serve the PAC file on (chosen automatically if unset)
+    pub pac_port: Option<u16>,
     pub description: Option<String>,
 }
 
+/// What carries a tunnel's forwards
+#[derive(Deserialize, Serialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelTransport {
+    #[default]
+    Ssh,
+    Kubectl,
+}
+
+/// Tunnel profiles that are started together with `remotec tunnel @<name>`
 #[derive(Deserialize, Serialize)]
+pub struct TunnelGroup {
+    pub name: String,
+    /// Names of the tunnel profiles
+    pub tunnels: Vec<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReconnectPolicy {
+    /// Give up after this many failed attempts in a row (or never, if unset)
+    pub max_attempts: Option<u32>,
+    /// Seconds to wait before the first attempt, which doubles after each failure
+    pub initial_delay: Option<f64>,
+    /// The longest to wait between attempts, in seconds
+    pub max_delay: Option<f64>,
+    /// Fraction by which to randomly vary each delay, e.g. `0.2` for ±20%
+    pub jitter: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct SshForwardArgument {
-    pub local_port: u16,
-    pub remote_port: u16,
-    pub remote_host: String,
+    /// A name to refer to the forward by in `open` templates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Environment variables for `remotec tunnel <name> --env` and `--exec`, as templates that
+    /// may refer to the local ports
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub forward: Forward,
+}
+
+/// A port forward, equivalent to ssh's `-L`, `-R` or `-D` options
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "RawForward", into = "RawForward")]
+pub enum Forward {
+    /// Listens on the local machine, and connects to the target from the remote host
+    Local {
+        listen: ForwardListen,
+        target: ForwardTarget,
+    },
+    /// Listens on the remote host, and connects to the target from the local machine
+    Remote {
+        listen: ForwardListen,
+        target: ForwardTarget,
+    },
+    /// A SOCKS proxy on the local machine, which connects from the remote host
+    Dynamic {
+        bind_address: Option<String>,
+        port: u16,
+    },
+}
+
+/// Where a forward listens for connections
+#[derive(Clone)]
+pub enum ForwardListen {
+    /// A TCP port on the loopback address (unless `bind_address` is set), where 0 is any free port
+    Port {
+        bind_address: Option<String>,
+        port: u16,
+    },
+    /// A Unix-domain socket
+    Socket(String),
+}
+
+/// Where a forward connects to
+#[derive(Clone)]
+pub enum ForwardTarget {
+    Host {
+        host: String,
+        port: u16,
+    },
+    /// A Unix-domain socket
+    Socket(String),
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+enum ForwardType {
+    #[default]
+    Local,
+    Remote,
+    Dynamic,
+}
+
+/// How a forward is written in the config file (where it is a local forward unless a `type` is
+/// given)
+#[derive(Deserialize, Serialize, Default)]
+struct RawForward {
+    #[serde(rename = "type", default)]
+    kind: ForwardType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind_address: Option<String>,
+    /// The local port, or `0`/`"auto"` to choose a free port
+    #[serde(
+        default,
+        deserialize_with = "local_port",
+        skip_serializing_if = "Option::is_none"
+    )]
+    local_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_socket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_bind_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_socket: Option<String>,
+}
+
+impl TryFrom<RawForward> for Forward {
+    type Error = String;
+
+    fn try_from(raw: RawForward) -> Result<Self, Self::Error> {
+        match raw.kind {
+            ForwardType::Local => Ok(Forward::Local {
+                listen: ForwardListen::new(
+                    raw.local_socket,
+                    raw.bind_address,
+                    raw.local_port,
+                    "local",
+                )?,
+                target: ForwardTarget::new(
+                    raw.remote_socket,
+                    Some(raw.remote_host.unwrap_or_else(|| "localhost".to_string())),
+                    raw.remote_port,
+                    "remote",
+                )?,
+            }),
+            ForwardType::Remote => Ok(Forward::Remote {
+                listen: ForwardListen::new(
+                    raw.remote_socket,
+                    raw.remote_bind_address,
+                    raw.remote_port,
+                    "remote",
+                )?,
+                target: ForwardTarget::new(
+                    raw.local_socket,
+                    Some(raw.local_host.unwrap_or_else(|| "localhost".to_string())),
+                    raw.local_port,
+                    "local",
+                )?,
+            }),
+            ForwardType::Dynamic => Ok(Forward::Dynamic {
+                bind_address: raw.bind_address,
+                port: raw
+                    .local_port
+                    .ok_or("a dynamic forward needs a `local_port`")?,
+            }),
+        }
+    }
+}
+
+impl From<Forward> for RawForward {
+    fn from(forward: Forward) -> Self {
+        let mut raw = RawForward::default();
+        match forward {
+            Forward::Local { listen, target } => {
+                match listen {
+                    ForwardListen::Port { bind_address, port } => {
+                        raw.bind_address = bind_address;
+                        raw.local_port = Some(port);
+                    }
+                    ForwardListen::Socket(path) => raw.local_socket = Some(path),
+                }
+                match target {
+                    ForwardTarget::Host { host, port } => {
+                        raw.remote_host = Some(host);
+                        raw.remote_port = Some(port);
+                    }
+                    ForwardTarget::Socket(path) => raw.remote_socket = Some(path),
+                }
+            }
+            Forward::Remote { listen, target } => {
+                raw.kind = ForwardType::Remote;
+                match listen {
+                    ForwardListen::Port { bind_address, port } => {
+                        raw.remote_bind_address = bind_address;
+                        raw.remote_port = Some(port);
+                    }
+                    ForwardListen::Socket(path) => raw.remote_socket = Some(path),
+                }
+                match target {
+                    ForwardTarget::Host { host, port } => {
+                        raw.local_host = Some(host);
+                        raw.local_port = Some(port);
+                    }
+                    ForwardTarget::Socket(path) => raw.local_socket = Some(path),
+                }
+            }
+            Forward::Dynamic { bind_address, port } => {
+                raw.kind = ForwardType::Dynamic;
+                raw.bind_address = bind_address;
+                raw.local_port = Some(port);
+            }
+        }
+        raw
+    }
+}
+
+impl ForwardListen {
+    fn new(
+        socket: Option<String>,
+        bind_address: Option<String>,
+        port: Option<u16>,
+        side: &str,
+    ) -> Result<Self, String> {
+        match (socket, port) {
+            (Some(path), None) => Ok(ForwardListen::Socket(path)),
+            (None, Some(port)) => Ok(ForwardListen::Port { bind_address, port }),
+            (Some(_), Some(_)) => Err(format!(
+                "a forward can't listen on both a `{side}_port` and a `{side}_socket`"
+            )),
+            (None, None) => Err(format!(
+                "a forward needs a `{side}_port` or `{side}_socket`"
+            )),
+        }
+    }
+}
+
+impl ForwardTarget {
+    fn new(
+        socket: Option<String>,
+        host: Option<String>,
+        port: Option<u16>,
+        side: &str,
+    ) -> Result<Self, String> {
+        match (socket, port) {
+            (Some(path), None) => Ok(ForwardTarget::Socket(path)),
+            (None, Some(0)) => Err(format!(
+                "a forward can't connect to an \"auto\" {side} port"
+            )),
+            (None, Some(port)) => Ok(ForwardTarget::Host {
+                host: host.ok_or(format!("a forward needs a `{side}_host`"))?,
+                port,
+            }),
+            (Some(_), Some(_)) => Err(format!(
+                "a forward can't connect to both a `{side}_port` and a `{side}_socket`"
+            )),
+            (None, None) => Err(format!(
+                "a forward needs a `{side}_port` or `{side}_socket`"
+            )),
+        }
+    }
+}
+
+/// Accepts either a single string or a list
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// Accepts a port number, or `"auto"` (which is stored as 0)
+fn local_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum LocalPort {
+        Port(u16),
+        Keyword(String),
+    }
+    match LocalPort::deserialize(deserializer)? {
+        LocalPort::Port(port) => Ok(Some(port)),
+        LocalPort::Keyword(k) if k == "auto" => Ok(Some(0)),
+        LocalPort::Keyword(k) => Err(D::Error::custom(format!(
+            "invalid local port `{k}`, expected a number or \"auto\""
+        ))),
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -105,13 +486,36 @@ pub struct SshJumpHost {
     pub username: Option<String>,
     pub hostname: String,
     pub port: Option<u16>,
+    /// Pinned host keys, as public keys (`ssh-ed25519 AAAA...`) or `SHA256:` fingerprints
+    #[serde(default)]
+    pub host_keys: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct CommandProfile {
     pub name: String,
-    pub ssh_profile: String,
+    /// The SSH profiles to run the command on: names, glob patterns (`web-*`) or tags (`tag:web`)
+    #[serde(deserialize_with = "one_or_many")]
+    pub ssh_profile: Vec<String>,
+    /// The remote command, which may contain `{name}` placeholders for the parameters
     pub command: Vec<String>,
+    #[serde(default)]
+    pub parameters: Vec<CommandParameter>,
+    pub description: Option<String>,
+}
+
+/// A value to fill in to a command, with `--set name=value` or as a positional argument
+#[derive(Deserialize, Serialize)]
+pub struct CommandParameter {
+    pub name: String,
+    pub default: Option<String>,
+    /// Whether a value must be given (by default, only if there's no `default`)
+    pub required: Option<bool>,
+    /// The only values that are accepted
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// A regular expression that the whole value must match
+    pub pattern: Option<String>,
     pub description: Option<String>,
 }
 
@@ -120,12 +524,17 @@ pub struct Config {
     pub rdp: Vec<RdpProfile>,
     pub ssh: Vec<SshProfile>,
     pub tunnels: Vec<TunnelProfile>,
+    pub tunnel_groups: Vec<TunnelGroup>,
     pub commands: Vec<CommandProfile>,
     pub rdp_defaults: RdpDefaults,
     pub ssh_defaults: SshDefaults,
 }
 
+/// The config file, unless another is given with `REMOTEC_CONFIG`
 pub fn config_path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = std::env::var_os("REMOTEC_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
     Ok(dirs::config_dir()
         .context("Unable to get config directory")?
         .join("remotec")
@@ -152,12 +561,18 @@ impl Config {
             bail!("Try again after saving your config changes")
         }
         let cfg_file = fs::read_to_string(&config_path).context("Unable to read config file")?;
+        Self::parse(&cfg_file)
+    }
+
+    /// Parses the contents of the config file, along with any files it includes
+    pub fn parse(cfg_file: &str) -> anyhow::Result<Self> {
         let cfg_file: ConfigFile =
-            serde_json::from_str(&cfg_file).context("Unable to deserialize config file")?;
+            serde_json::from_str(cfg_file).context("Unable to deserialize config file")?;
         let mut config = Config {
             rdp: cfg_file.this.rdp,
             ssh: cfg_file.this.ssh,
             tunnels: cfg_file.this.tunnels,
+            tunnel_groups: cfg_file.this.tunnel_groups,
             commands: cfg_file.this.commands,
             rdp_defaults: cfg_file.rdp_defaults,
             ssh_defaults: cfg_file.ssh_defaults,
@@ -167,6 +582,7 @@ impl Config {
                 config.rdp.append(&mut s.rdp);
                 config.ssh.append(&mut s.ssh);
                 config.tunnels.append(&mut s.tunnels);
+                config.tunnel_groups.append(&mut s.tunnel_groups);
                 config.commands.append(&mut s.commands);
             }
         }
@@ -196,16 +612,161 @@ fn load_satellite_config(path: &Path) -> Option<SatelliteConfig> {
     Some(config)
 }
 
-#[derive(Deserialize, Serialize, Copy, Clone)]
+#[derive(Deserialize, Serialize, Copy, Clone, Default)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum GatewayPolicy {
     Disable = 0,
     Enable = 1,
+    #[default]
     Fallback = 2,
 }
 
-impl Default for GatewayPolicy {
-    fn default() -> Self {
-        Self::Fallback
+/// Profiles and configs for tests, built from JSON with the required fields filled in
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// `base` with the fields of `overrides` added or replaced
+    pub fn merge(mut base: Value, overrides: Value) -> Value {
+        let overrides = overrides.as_object().unwrap().clone();
+        base.as_object_mut().unwrap().extend(overrides);
+        base
+    }
+
+    /// The JSON of an SSH profile named `test` for `example.com`
+    pub fn ssh_json(overrides: Value) -> Value {
+        merge(
+            json!({"name": "test", "hostname": "example.com"}),
+            overrides,
+        )
+    }
+
+    pub fn ssh_profile(overrides: Value) -> SshProfile {
+        serde_json::from_value(ssh_json(overrides)).unwrap()
+    }
+
+    /// A tunnel profile named `test` over the `test` SSH profile, with no forwards
+    pub fn tunnel_profile(overrides: Value) -> TunnelProfile {
+        let base = json!({"name": "test", "ssh_profile": "test", "forwards": []});
+        serde_json::from_value(merge(base, overrides)).unwrap()
+    }
+
+    /// A config parsed the same way as the config file
+    pub fn config(config: Value) -> Config {
+        Config::parse(&config.to_string()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Parses a forward and writes it back out
+    fn round_trip(forward: Value) -> Value {
+        let forward: Forward = serde_json::from_value(forward).unwrap();
+        serde_json::to_value(forward).unwrap()
+    }
+
+    fn error(forward: Value) -> String {
+        match serde_json::from_value::<Forward>(forward) {
+            Ok(_) => panic!("Forward should be invalid"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn forward_types() {
+        assert_eq!(
+            round_trip(json!({"local_port": 8080, "remote_port": 80})),
+            json!({
+                "type": "local",
+                "local_port": 8080,
+                "remote_port": 80,
+                "remote_host": "localhost"
+            })
+        );
+        assert_eq!(
+            round_trip(json!({
+                "type": "remote",
+                "remote_bind_address": "0.0.0.0",
+                "remote_port": 9000,
+                "local_port": 3000
+            })),
+            json!({
+                "type": "remote",
+                "remote_bind_address": "0.0.0.0",
+                "remote_port": 9000,
+                "local_host": "localhost",
+                "local_port": 3000
+            })
+        );
+        assert_eq!(
+            round_trip(json!({"type": "dynamic", "bind_address": "*", "local_port": "auto"})),
+            json!({"type": "dynamic", "bind_address": "*", "local_port": 0})
+        );
+    }
+
+    #[test]
+    fn socket_forwards() {
+        let local = json!({
+            "type": "local",
+            "local_socket": "/tmp/docker.sock",
+            "remote_socket": "/run/docker.sock"
+        });
+        assert_eq!(round_trip(local.clone()), local);
+        let remote = json!({
+            "type": "remote",
+            "remote_socket": "/tmp/agent.sock",
+            "local_socket": "/run/agent.sock"
+        });
+        assert_eq!(round_trip(remote.clone()), remote);
+        // A socket can be forwarded to a port, and the other way around
+        let mixed = json!({
+            "type": "local",
+            "local_socket": "/tmp/db.sock",
+            "remote_port": 5432,
+            "remote_host": "db"
+        });
+        assert_eq!(round_trip(mixed.clone()), mixed);
+    }
+
+    #[test]
+    fn invalid_forwards() {
+        let cases = [
+            (
+                json!({"remote_port": 80}),
+                "needs a `local_port` or `local_socket`",
+            ),
+            (
+                json!({"local_port": 8080}),
+                "needs a `remote_port` or `remote_socket`",
+            ),
+            (
+                json!({"local_port": 8080, "local_socket": "/tmp/s", "remote_port": 80}),
+                "can't listen on both a `local_port` and a `local_socket`",
+            ),
+            (
+                json!({"local_port": 8080, "remote_port": 80, "remote_socket": "/tmp/s"}),
+                "can't connect to both a `remote_port` and a `remote_socket`",
+            ),
+            (
+                json!({"type": "remote", "remote_port": 9000, "local_port": "auto"}),
+                "can't connect to an \"auto\" local port",
+            ),
+            (
+                json!({"type": "remote", "local_port": 3000}),
+                "needs a `remote_port` or `remote_socket`",
+            ),
+            (
+                json!({"type": "dynamic"}),
+                "a dynamic forward needs a `local_port`",
+            ),
+        ];
+        for (forward, expected) in cases {
+            let error = error(forward);
+            assert!(error.contains(expected), "{error}");
+        }
     }
 }
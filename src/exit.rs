@@ -0,0 +1,126 @@
+//! Exit codes used by remotec.
+//!
+//! When remotec launches a program (e.g. ssh) it exits with that program's status, so the
+//! failures that originate in remotec itself use codes from `sysexits.h` to keep them apart.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::process::ExitStatus;
+
+/// A generic internal error
+pub const EXIT_SOFTWARE: i32 = 70;
+
+/// A failure that originated in remotec rather than the program it launched.
+///
+/// Attach one to an error with `.context()`, and `main` will exit with its code.
+#[derive(Debug, Copy, Clone)]
+pub enum Failure {
+    /// The config file couldn't be created, read or parsed
+    Config,
+    /// No profile exists with the requested name
+    ProfileNotFound,
+    /// The program needed to make the connection couldn't be found
+    BackendMissing,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 78,
+            Failure::ProfileNotFound => 66,
+            Failure::BackendMissing => 69,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Config => write!(f, "Invalid config"),
+            Failure::ProfileNotFound => write!(f, "Unknown profile"),
+            Failure::BackendMissing => write!(f, "Backend unavailable"),
+        }
+    }
+}
+
+/// Wraps an error from launching `program`, flagging it as a missing backend if it wasn't found
+pub fn launch_error(error: io::Error, program: &str) -> anyhow::Error {
+    let not_found = error.kind() == io::ErrorKind::NotFound;
+    let error = anyhow::Error::new(error).context(format!("Error invoking {program}"));
+    if not_found {
+        error.context(Failure::BackendMissing)
+    } else {
+        error
+    }
+}
+
+/// Gets the exit code for an error returned by `run`
+pub fn error_code(error: &anyhow::Error) -> i32 {
+    error
+        .downcast_ref::<Failure>()
+        .map(|f| f.exit_code())
+        .unwrap_or(EXIT_SOFTWARE)
+}
+
+/// Converts the exit status of a child process into an exit code for remotec,
+/// using the shell convention of 128 + signal number when it was killed by a signal
+pub fn status_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    EXIT_SOFTWARE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn failure_codes() {
+        let failed = |failure: Failure| {
+            let error: anyhow::Result<()> = Err(anyhow::anyhow!("cause")).context(failure);
+            error_code(&error.unwrap_err())
+        };
+        assert_eq!(failed(Failure::ProfileNotFound), 66);
+        assert_eq!(failed(Failure::BackendMissing), 69);
+        assert_eq!(failed(Failure::Config), 78);
+        assert_eq!(error_code(&anyhow::anyhow!("anything else")), 70);
+        // The failure can be anywhere in the chain of contexts
+        let error = anyhow::anyhow!("cause")
+            .context(Failure::Config)
+            .context("Loading config");
+        assert_eq!(error_code(&error), 78);
+    }
+
+    #[test]
+    fn launch_errors() {
+        let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert_eq!(error_code(&launch_error(missing, "ssh")), 69);
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(error_code(&launch_error(denied, "ssh")), 70);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_statuses() {
+        let status = |script: &str| {
+            std::process::Command::new("sh")
+                .args(["-c", script])
+                .status()
+                .unwrap()
+        };
+        assert_eq!(status_code(status("exit 0")), 0);
+        assert_eq!(status_code(status("exit 3")), 3);
+        // Killed by SIGTERM (15) and SIGKILL (9)
+        assert_eq!(status_code(status("kill -TERM $$")), 143);
+        assert_eq!(status_code(status("kill -KILL $$")), 137);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::background::{mark_ready, register, unregister};
+use crate::config::{SshBackend, SshForwardArgument, TunnelProfile, TunnelTransport};
+use crate::exit::status_code;
+use crate::pac::serve_pac;
+use crate::select::select_profile_by_name;
+use crate::ssh::{invoke_ssh, spawn_ssh, ssh_target, SshTarget};
+use crate::tunnel::{
+    choose_local_ports, connection_args, forward_args, log_forwards, open_locations,
+    print_group_env, wait_until_ready,
+};
+use crate::{Config, Tunnel};
+use anyhow::{bail, Context};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Set by Ctrl-C, so that the group's ssh processes are stopped and its state is removed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The exit code after Ctrl-C (128 + SIGINT)
+const INTERRUPTED_CODE: i32 = 130;
+
+/// Members of a group that share a connection to the same SSH profile
+struct Connection<'a> {
+    ssh_profile: &'a str,
+    members: Vec<(&'a TunnelProfile, Vec<SshForwardArgument>)>,
+    child: Option<Child>,
+}
+
+impl Connection<'_> {
+    fn forwards(&self) -> Vec<SshForwardArgument> {
+        self.members
+            .iter()
+            .flat_map(|(_, forwards)| forwards.iter().cloned())
+            .collect()
+    }
+
+    fn member_names(&self) -> String {
+        let names = self
+            .members
+            .iter()
+            .map(|(m, _)| format!("`{}`", m.name))
+            .collect::<Vec<_>>();
+        names.join(", ")
+    }
+}
+
+pub fn launch_group(config: &Config, cli: &Tunnel, name: &str) -> anyhow::Result<i32> {
+    let group = select_profile_by_name("Tunnel group", &config.tunnel_groups, name, true)?;
+    if cli.background || cli.exec {
+        bail!("Tunnel groups can't be started with --background or --exec");
+    }
+    if cli.install_systemd || cli.uninstall_systemd {
+        bail!(
+            "Tunnel groups don't have systemd units, install them for the group's tunnels instead"
+        );
+    }
+    let members = group
+        .tunnels
+        .iter()
+        .map(|t| select_profile_by_name("Tunnel", &config.tunnels, t, false))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if members.is_empty() {
+        bail!("Group doesn't contain any tunnels");
+    }
+    if cli.env {
+        return print_group_env(&members, cli.shell);
+    }
+
+    // Choose all the ports at once so that the members' automatic ports don't collide
+    let all_forwards = members
+        .iter()
+        .flat_map(|m| m.forwards.iter().cloned())
+        .collect::<Vec<_>>();
+    let mut all_forwards = choose_local_ports(&all_forwards)?.into_iter();
+    let mut connections: Vec<Connection> = Vec::new();
+    for member in members {
+        if member.transport != TunnelTransport::Ssh {
+            bail!(
+                "Tunnel groups only support SSH tunnels, but `{}` uses kubectl",
+                member.name
+            );
+        }
+        if member.relay || member.idle_timeout.is_some() {
+            log::warn!(
+                "Tunnel groups aren't relayed, ignoring `relay` and `idle_timeout` of `{}`",
+                member.name
+            );
+        }
+        if member.reconnect.is_some() {
+            log::warn!(
+                "Tunnel groups don't reconnect, ignoring `reconnect` of `{}`",
+                member.name
+            );
+        }
+        let forwards = all_forwards.by_ref().take(member.forwards.len()).collect();
+        match connections
+            .iter_mut()
+            .find(|c| Some(c.ssh_profile) == member.ssh_profile.as_deref())
+        {
+            Some(connection) => {
+                // The members share one ssh process, so they must agree on its keepalives
+                let first = connection.members[0].0;
+                if connection_args(first) != connection_args(member) {
+                    bail!(
+                        "`{}` and `{}` use the same SSH profile so share a connection, but have \
+                        different `server_alive_interval` or `server_alive_count_max`",
+                        first.name,
+                        member.name
+                    );
+                }
+                connection.members.push((member, forwards))
+            }
+            None => connections.push(Connection {
+                ssh_profile: member.ssh_profile_name()?,
+                members: vec![(member, forwards)],
+                child: None,
+            }),
+        }
+    }
+
+    let mut targets = Vec::new();
+    for connection in &connections {
+        let mut target = ssh_target(config, &cli.common, connection.ssh_profile, false)?;
+        if let SshBackend::Native = target.backend {
+            bail!("Tunnel groups need the OpenSSH backend");
+        }
+        let forwards = connection.forwards();
+        log_forwards(&forwards);
+        target.options.extend(forward_args(&forwards));
+        target
+            .options
+            .extend(connection_args(connection.members[0].0));
+        targets.push(target);
+    }
+    if cli.common.stdout {
+        for target in targets {
+            invoke_ssh(target, Vec::new(), true)?;
+        }
+        return Ok(0);
+    }
+
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
+        .context("Unable to handle Ctrl-C")?;
+    for connection in &connections {
+        for (member, forwards) in &connection.members {
+            register(member, forwards)?;
+        }
+    }
+    let result = connections
+        .iter()
+        .flat_map(|c| &c.members)
+        .try_for_each(|(member, forwards)| serve_pac(member, forwards).map(|_| ()))
+        .and_then(|_| run_group(&mut connections, targets));
+    for connection in &mut connections {
+        if let Some(child) = &mut connection.child {
+            let _ = child.kill();
+        }
+        for (member, _) in &connection.members {
+            unregister(member);
+        }
+    }
+    result
+}
+
+fn run_group(connections: &mut [Connection], targets: Vec<SshTarget>) -> anyhow::Result<i32> {
+    for (connection, target) in connections.iter_mut().zip(targets) {
+        target.preflight()?;
+        connection.child = Some(spawn_ssh(target, Vec::new())?);
+    }
+
+    let mut report = Vec::new();
+    for connection in connections.iter_mut() {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Ok(INTERRUPTED_CODE);
+        }
+        let forwards = connection.forwards();
+        let child = connection.child.as_mut().unwrap();
+        let status = match wait_until_ready(&forwards, child, "ssh") {
+            Ok(()) => {
+                for (member, forwards) in &connection.members {
+                    if let Err(e) = mark_ready(member) {
+                        log::warn!("Unable to record that the tunnel is ready: {e:#}");
+                    }
+                    open_locations(member, forwards);
+                }
+                "ready".to_string()
+            }
+            Err(e) => {
+                let _ = child.kill();
+                connection.child = None;
+                format!("failed: {e:#}")
+            }
+        };
+        for (member, _) in &connection.members {
+            report.push((member.name.clone(), connection.ssh_profile, status.clone()));
+        }
+    }
+    for (member, ssh_profile, status) in report {
+        println!("{member}\t{ssh_profile}\t{status}");
+    }
+
+    let mut failed = connections.iter().any(|c| c.child.is_none());
+    while connections.iter().any(|c| c.child.is_some()) {
+        // Ctrl-C also interrupts ssh, so check this before reporting its connections as closed
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            log::info!("Closing the group's tunnels");
+            return Ok(INTERRUPTED_CODE);
+        }
+        for connection in connections.iter_mut() {
+            let Some(child) = &mut connection.child else {
+                continue;
+            };
+            if let Some(status) = child.try_wait()? {
+                log::warn!(
+                    "Connection to `{}` for {} closed ({})",
+                    connection.ssh_profile,
+                    connection.member_names(),
+                    status_code(status)
+                );
+                connection.child = None;
+                failed = true;
+                for (member, _) in &connection.members {
+                    unregister(member);
+                }
+            }
+        }
+        sleep(Duration::from_millis(200));
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::config;
+
+    #[test]
+    fn unsupported_flags() {
+        let config = config(serde_json::json!({
+            "tunnels": [{"name": "db", "ssh_profile": "s", "forwards": []}],
+            "tunnel_groups": [{"name": "g", "tunnels": ["db"]}]
+        }));
+        let flags = [
+            Tunnel {
+                background: true,
+                ..Default::default()
+            },
+            Tunnel {
+                install_systemd: true,
+                socket: true,
+                ..Default::default()
+            },
+            Tunnel {
+                uninstall_systemd: true,
+                ..Default::default()
+            },
+        ];
+        for cli in flags {
+            let error = launch_group(&config, &cli, "g").unwrap_err().to_string();
+            assert!(error.starts_with("Tunnel groups "), "{error}");
+        }
+    }
+}
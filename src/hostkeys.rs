@@ -0,0 +1,396 @@
+use crate::config::{SshJumpHost, SshProfile};
+use crate::exit::launch_error;
+use crate::runtime::{file_name, runtime_subdirectory, short_hash, write_atomic};
+use crate::select::select_profile_by_name;
+use crate::{Config, Hostkeys, HostkeysAction};
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A host in the connection chain, along with the keys pinned for it
+pub struct KnownHost<'a> {
+    hostname: &'a str,
+    port: Option<u16>,
+    /// The name of the host as it appears in a known_hosts file
+    name: String,
+    pins: &'a [String],
+}
+
+impl<'a> KnownHost<'a> {
+    pub fn new(hostname: &'a str, port: Option<u16>, pins: &'a [String]) -> Self {
+        // ssh records hosts on non-standard ports as `[host]:port`
+        let name = match port {
+            Some(port) if port != 22 => format!("[{hostname}]:{port}"),
+            _ => hostname.to_string(),
+        };
+        Self {
+            hostname,
+            port,
+            name,
+            pins,
+        }
+    }
+
+    pub fn jump_host(jump: &'a SshJumpHost) -> Self {
+        Self::new(&jump.hostname, jump.port, &jump.host_keys)
+    }
+}
+
+enum Pin<'a> {
+    PublicKey { kind: &'a str, blob: &'a str },
+    Fingerprint(&'a str),
+}
+
+impl<'a> Pin<'a> {
+    fn parse(pin: &'a str) -> anyhow::Result<Self> {
+        if pin.starts_with("SHA256:") {
+            return Ok(Pin::Fingerprint(pin));
+        }
+        let mut parts = pin.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(kind), Some(blob)) => Ok(Pin::PublicKey { kind, blob }),
+            _ => bail!("Pinned host key `{pin}` should be a public key or a SHA256 fingerprint"),
+        }
+    }
+
+    fn fingerprint(&self) -> anyhow::Result<String> {
+        match self {
+            Pin::PublicKey { blob, .. } => fingerprint(blob),
+            Pin::Fingerprint(fp) => Ok(fp.to_string()),
+        }
+    }
+}
+
+/// A key from the user's known_hosts file
+struct KnownKey {
+    kind: String,
+    blob: String,
+}
+
+/// Fingerprint of a public key in the same format as `ssh-keygen -l`
+pub fn fingerprint(blob: &str) -> anyhow::Result<String> {
+    let key = STANDARD
+        .decode(blob)
+        .context("Unable to decode host public key")?;
+    Ok(format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(key))
+    ))
+}
+
+/// Whether a public key (base64 encoded) matches any of the pinned keys
+pub fn matches_pins(pins: &[String], blob: &str) -> anyhow::Result<bool> {
+    let fp = fingerprint(blob)?;
+    for pin in pins {
+        if Pin::parse(pin)?.fingerprint()? == fp {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The known_hosts files ssh reads for a host (its `UserKnownHostsFile`), or the default one if
+/// ssh can't tell us
+fn user_known_hosts_files(host: &KnownHost) -> anyhow::Result<Vec<PathBuf>> {
+    let mut command = Command::new("ssh");
+    command.arg("-G");
+    if let Some(port) = host.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    command.arg(host.hostname).stdin(Stdio::null());
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            let files = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|l| l.strip_prefix("userknownhostsfile "))
+                .map(|files| {
+                    files
+                        .split_whitespace()
+                        .filter(|f| *f != "none")
+                        .map(PathBuf::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+            return Ok(files);
+        }
+        Ok(output) => log::debug!(
+            "Unable to get the ssh config for `{}`: {}",
+            host.name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) => log::debug!("Unable to run ssh: {err}"),
+    }
+    let home = dirs::home_dir().context("Unable to get home directory")?;
+    Ok(vec![home.join(".ssh").join("known_hosts")])
+}
+
+/// Looks up a host's keys in some known_hosts files (including hashed entries)
+fn known_keys(files: &[PathBuf], name: &str) -> anyhow::Result<Vec<KnownKey>> {
+    let mut keys = Vec::new();
+    for file in files.iter().filter(|f| f.exists()) {
+        let output = Command::new("ssh-keygen")
+            .arg("-F")
+            .arg(name)
+            .arg("-f")
+            .arg(file)
+            .output()
+            .map_err(|e| launch_error(e, "ssh-keygen"))?;
+        keys.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|l| !l.starts_with('#') && !l.starts_with('@'))
+                .filter_map(|l| {
+                    let mut parts = l.split_whitespace().skip(1);
+                    Some(KnownKey {
+                        kind: parts.next()?.to_string(),
+                        blob: parts.next()?.to_string(),
+                    })
+                }),
+        );
+    }
+    Ok(keys)
+}
+
+/// Looks up a host's keys in the user's known_hosts files
+fn user_known_keys(host: &KnownHost) -> anyhow::Result<Vec<KnownKey>> {
+    known_keys(&user_known_hosts_files(host)?, &host.name)
+}
+
+/// Options that restrict ssh to the pinned host keys, if any hosts in the chain have pins.
+///
+/// The options are written to a generated ssh config file (which includes the user's own config)
+/// rather than passed with `-o`, because ssh only forwards a config file to its jump connections.
+pub fn pinning_args(profile: &SshProfile, hosts: &[KnownHost]) -> anyhow::Result<Vec<String>> {
+    if hosts.iter().all(|h| h.pins.is_empty()) {
+        return Ok(Vec::new());
+    }
+    let lines = pinned_known_hosts(hosts, user_known_keys)?;
+
+    // The files depend on the hosts in the chain (e.g. whether jump hosts are used), so they're
+    // named by their contents, in case the profile is being launched with other hosts at the same
+    // time
+    let dir = runtime_subdirectory("hostkeys")?;
+    let name = format!("{}-{}", file_name(&profile.name), short_hash(&lines));
+    let known_hosts = dir.join(format!("{name}.known_hosts"));
+    write_atomic(&known_hosts, lines).context("Unable to write known_hosts")?;
+    let config_path = dir.join(format!("{name}.config"));
+    write_atomic(&config_path, pinning_config(&known_hosts))
+        .context("Unable to write ssh config")?;
+
+    Ok(vec!["-F".to_string(), config_path.display().to_string()])
+}
+
+/// The known_hosts file for a chain of hosts: the pinned keys, and the user's known keys for any
+/// hosts without pins
+fn pinned_known_hosts(
+    hosts: &[KnownHost],
+    known_keys: impl Fn(&KnownHost) -> anyhow::Result<Vec<KnownKey>>,
+) -> anyhow::Result<String> {
+    let mut lines = Vec::new();
+    for host in hosts {
+        if host.pins.is_empty() {
+            let known = known_keys(host)?;
+            if known.is_empty() {
+                bail!(
+                    "`{}` has no pinned host keys and isn't in your known_hosts, so it can't be \
+                    checked along with the pinned hosts (pin its keys with `host_keys`)",
+                    host.name
+                );
+            }
+            for key in known {
+                lines.push(format!("{} {} {}", host.name, key.kind, key.blob));
+            }
+            continue;
+        }
+        for pin in host.pins {
+            match Pin::parse(pin)? {
+                Pin::PublicKey { kind, blob } => {
+                    lines.push(format!("{} {kind} {blob}", host.name));
+                }
+                Pin::Fingerprint(fp) => {
+                    // known_hosts needs the whole public key, so find it in the user's file
+                    let key = known_keys(host)?
+                        .into_iter()
+                        .find(|k| fingerprint(&k.blob).ok().as_deref() == Some(fp))
+                        .with_context(|| {
+                            format!(
+                                "Host key {fp} pinned for `{}` isn't in your known_hosts, \
+                                pin its public key instead",
+                                host.name
+                            )
+                        })?;
+                    lines.push(format!("{} {} {}", host.name, key.kind, key.blob));
+                }
+            }
+        }
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+/// The ssh config that only trusts the keys in `known_hosts`, and otherwise uses the user's config
+fn pinning_config(known_hosts: &Path) -> String {
+    let mut config = vec![
+        format!("UserKnownHostsFile \"{}\"", known_hosts.display()),
+        "GlobalKnownHostsFile none".to_string(),
+        "StrictHostKeyChecking yes".to_string(),
+    ];
+    // Each include gets its own `Match all` block, so its settings apply to every host whatever
+    // block the file before it ended with
+    let mut includes = vec!["~/.ssh/config"];
+    if cfg!(unix) {
+        includes.push("/etc/ssh/ssh_config");
+    }
+    for include in includes {
+        config.push("Match all".to_string());
+        config.push(format!("Include {include}"));
+    }
+    config.push(String::new());
+    config.join("\n")
+}
+
+pub fn launch_hostkeys(config: &Config, cli: &Hostkeys) -> anyhow::Result<i32> {
+    let HostkeysAction::Verify { name } = &cli.action;
+    let profiles = match name {
+        Some(name) => vec![select_profile_by_name("SSH", &config.ssh, name, false)?],
+        None => config.ssh.iter().collect(),
+    };
+    verify(&profiles, user_known_keys)
+}
+
+/// Prints whether the known keys of each pinned host match its pins, and returns 1 if any don't
+fn verify(
+    profiles: &[&SshProfile],
+    known_keys: impl Fn(&KnownHost) -> anyhow::Result<Vec<KnownKey>>,
+) -> anyhow::Result<i32> {
+    let mut mismatches = 0;
+    for profile in profiles {
+        let mut hosts = profile
+            .jump_hosts
+            .iter()
+            .map(KnownHost::jump_host)
+            .collect::<Vec<_>>();
+        if let Ok(address) = profile.address.choose_address(false, false) {
+            hosts.push(KnownHost::new(
+                address,
+                profile.address.port,
+                &profile.host_keys,
+            ));
+        }
+        for host in hosts.iter().filter(|h| !h.pins.is_empty()) {
+            let known = known_keys(host)?;
+            let mut matched = false;
+            for key in &known {
+                matched |= matches_pins(host.pins, &key.blob)?;
+            }
+            let status = if known.is_empty() {
+                "not in known_hosts".to_string()
+            } else if matched {
+                "ok".to_string()
+            } else {
+                mismatches += 1;
+                let known = known
+                    .iter()
+                    .map(|k| format!("{} {}", k.kind, fingerprint(&k.blob).unwrap_or_default()))
+                    .collect::<Vec<_>>();
+                format!("MISMATCH (known_hosts has {})", known.join(", "))
+            };
+            println!("{}\t{}\t{}", profile.name, host.name, status);
+        }
+    }
+    if mismatches > 0 {
+        log::error!("{mismatches} host(s) don't match their pinned keys");
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::ssh_profile;
+    use serde_json::json;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE81bKa0oY3bjuYRkZ0O8XUuy1Td57zd1J4iXPaWk4/P";
+    const FINGERPRINT: &str = "SHA256:p0VJMu8Pg9gZDfOksBourpy/kzabUf2BbmdTxuXGKBc";
+    const OTHER_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    fn blob(key: &str) -> &str {
+        key.split_whitespace().nth(1).unwrap()
+    }
+
+    #[test]
+    fn pins() {
+        // The same as `ssh-keygen -l`
+        assert_eq!(fingerprint(blob(KEY)).unwrap(), FINGERPRINT);
+        assert!(matches_pins(&[KEY.to_string()], blob(KEY)).unwrap());
+        assert!(matches_pins(&[FINGERPRINT.to_string()], blob(KEY)).unwrap());
+        assert!(!matches_pins(&[OTHER_KEY.to_string()], blob(KEY)).unwrap());
+        assert!(matches_pins(&["ssh-ed25519".to_string()], blob(KEY)).is_err());
+        assert!(fingerprint("not base64!").is_err());
+    }
+
+    #[test]
+    fn known_hosts_for_chain() {
+        let pins = [KEY.to_string()];
+        let hosts = [
+            KnownHost::new("jump", None, &[]),
+            KnownHost::new("example.com", Some(2222), &pins),
+        ];
+        let known = |host: &KnownHost| {
+            Ok(match host.name.as_str() {
+                "jump" => vec![KnownKey {
+                    kind: "ssh-ed25519".to_string(),
+                    blob: blob(OTHER_KEY).to_string(),
+                }],
+                _ => Vec::new(),
+            })
+        };
+        assert_eq!(
+            pinned_known_hosts(&hosts, known).unwrap(),
+            format!("jump {OTHER_KEY}\n[example.com]:2222 {KEY}\n")
+        );
+        // Without pins or known keys, ssh would refuse to connect to the jump host
+        let error = pinned_known_hosts(&hosts, |_| Ok(Vec::new())).unwrap_err();
+        assert!(
+            error.to_string().contains("`jump` has no pinned"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn includes_user_config() {
+        let config = pinning_config(Path::new("/run/pins"));
+        let mut expected = "UserKnownHostsFile \"/run/pins\"\n\
+            GlobalKnownHostsFile none\n\
+            StrictHostKeyChecking yes\n\
+            Match all\n\
+            Include ~/.ssh/config\n"
+            .to_string();
+        if cfg!(unix) {
+            expected.push_str("Match all\nInclude /etc/ssh/ssh_config\n");
+        }
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn verify_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [dir.path().join("known_hosts")];
+        std::fs::write(&files[0], format!("example.com {KEY}\n")).unwrap();
+        let known = |host: &KnownHost| known_keys(&files, &host.name);
+
+        let profile = ssh_profile(json!({"host_keys": [FINGERPRINT]}));
+        assert_eq!(verify(&[&profile], known).unwrap(), 0);
+        let profile = ssh_profile(json!({"host_keys": [OTHER_KEY]}));
+        assert_eq!(verify(&[&profile], known).unwrap(), 1);
+        // Hosts that aren't known yet can't be compared
+        let profile = ssh_profile(json!({"hostname": "other.com", "host_keys": [OTHER_KEY]}));
+        assert_eq!(verify(&[&profile], known).unwrap(), 0);
+    }
+}
@@ -0,0 +1,168 @@
+//! Tunnels carried by `kubectl port-forward` to a Kubernetes service or pod
+
+use crate::config::{Forward, ForwardListen, ForwardTarget, SshForwardArgument, TunnelProfile};
+use crate::exit::launch_error;
+use crate::tunnel::listen_address;
+use anyhow::{bail, Context};
+use std::process::{Child, Command, Stdio};
+
+const KUBECTL: &str = "kubectl";
+
+/// The arguments for `kubectl` to forward the ports
+pub fn kubectl_args(
+    profile: &TunnelProfile,
+    forwards: &[SshForwardArgument],
+) -> anyhow::Result<Vec<String>> {
+    let target = profile
+        .target
+        .as_ref()
+        .context("A kubectl tunnel needs a `target`, e.g. `svc/grafana`")?;
+    let mut args = vec!["port-forward".to_string()];
+    if let Some(context) = &profile.context {
+        args.extend(["--context".to_string(), context.clone()]);
+    }
+    if let Some(namespace) = &profile.namespace {
+        args.extend(["--namespace".to_string(), namespace.clone()]);
+    }
+    args.push(target.clone());
+
+    let mut address = None;
+    let mut ports = Vec::new();
+    for f in forwards {
+        let (bind_address, local_port, host, remote_port) = match &f.forward {
+            Forward::Local {
+                listen: ForwardListen::Port { bind_address, port },
+                target: ForwardTarget::Host { host, port: remote },
+            } => (bind_address, port, host, remote),
+            _ => bail!(
+                "kubectl can only forward local TCP ports, not {}",
+                f.description()
+            ),
+        };
+        if !["localhost", "127.0.0.1", "::1"].contains(&host.as_str()) {
+            bail!(
+                "kubectl forwards connect to `{target}` itself, so can't reach `{host}` for {}",
+                f.description()
+            );
+        }
+        // kubectl listens on the same addresses for every port
+        let bind_address = listen_address(bind_address.as_deref());
+        if address.is_some_and(|a| a != bind_address) {
+            bail!("kubectl needs all the forwards to have the same `bind_address`");
+        }
+        address = Some(bind_address);
+        ports.push(format!("{local_port}:{remote_port}"));
+    }
+    if let Some(address) = address.filter(|a| *a != "127.0.0.1") {
+        args.extend(["--address".to_string(), address.to_string()]);
+    }
+    args.extend(ports);
+    Ok(args)
+}
+
+pub fn spawn_kubectl(args: &[String]) -> anyhow::Result<Child> {
+    log::info!(
+        "Invoking: `{}`",
+        shell_words::join(std::iter::once(KUBECTL).chain(args.iter().map(String::as_str)))
+    );
+    Command::new(KUBECTL)
+        .args(args)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| launch_error(e, KUBECTL))
+}
+
+/// Prints the kubectl command instead of running it
+pub fn print_kubectl(args: Vec<String>) -> anyhow::Result<i32> {
+    crate::ssh::invoke(KUBECTL, args, Vec::new(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::{merge, tunnel_profile};
+
+    fn args(profile: serde_json::Value) -> anyhow::Result<Vec<String>> {
+        let kubectl = serde_json::json!({"transport": "kubectl", "target": "svc/app"});
+        let profile = tunnel_profile(merge(kubectl, profile));
+        kubectl_args(&profile, &profile.forwards)
+    }
+
+    #[test]
+    fn argument_order() {
+        let args = args(serde_json::json!({
+            "context": "prod",
+            "namespace": "monitoring",
+            "forwards": [
+                {"local_port": 3000, "remote_port": 80, "bind_address": "0.0.0.0"},
+                {"local_port": 3001, "remote_host": "127.0.0.1", "remote_port": 81, "bind_address": "0.0.0.0"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "port-forward",
+                "--context",
+                "prod",
+                "--namespace",
+                "monitoring",
+                "svc/app",
+                "--address",
+                "0.0.0.0",
+                "3000:80",
+                "3001:81"
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let args = args(serde_json::json!({"forwards": [{"local_port": 3000, "remote_port": 80}]}));
+        assert_eq!(args.unwrap(), ["port-forward", "svc/app", "3000:80"]);
+    }
+
+    #[test]
+    fn rejects_other_hosts() {
+        let error = args(serde_json::json!({
+            "forwards": [{"local_port": 5432, "remote_host": "db.internal", "remote_port": 5432}]
+        }))
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("can't reach `db.internal`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_bind_address() {
+        let error = args(serde_json::json!({
+            "forwards": [
+                {"local_port": 3000, "remote_port": 80},
+                {"local_port": 3001, "remote_port": 81, "bind_address": "0.0.0.0"}
+            ]
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("same `bind_address`"), "{error}");
+    }
+
+    #[test]
+    fn rejects_remote_and_dynamic_forwards() {
+        for forward in [
+            serde_json::json!({"type": "remote", "remote_port": 9000, "local_port": 3000}),
+            serde_json::json!({"type": "dynamic", "local_port": 1080}),
+        ] {
+            let error = args(serde_json::json!({"forwards": [forward]})).unwrap_err();
+            assert!(
+                error.to_string().contains("only forward local TCP ports"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn needs_target() {
+        let profile = tunnel_profile(serde_json::json!({"transport": "kubectl"}));
+        assert!(kubectl_args(&profile, &[]).is_err());
+    }
+}
@@ -1,16 +1,36 @@
 mod address;
+mod askpass;
+mod background;
 mod command;
 mod config;
+mod exit;
+mod group;
+mod hostkeys;
+mod kubectl;
+mod mux;
+#[cfg(feature = "native-ssh")]
+mod native;
+mod pac;
+mod ping;
+mod preflight;
 mod rdp;
+mod relay;
+mod runtime;
 mod select;
+mod session;
 mod ssh;
+mod systemd;
 mod tunnel;
 
 use crate::command::launch_command;
 use crate::config::Config;
+use crate::exit::Failure;
+use crate::hostkeys::launch_hostkeys;
+use crate::mux::launch_mux;
+use crate::ping::{launch_ping, PingKind};
 use crate::rdp::launch_rdp;
 use crate::ssh::launch_ssh;
-use crate::tunnel::launch_tunnel;
+use crate::tunnel::{launch_tunnel, Shell};
 use anyhow::Context;
 use clap::{Args, Parser};
 use env_logger::{Env, Target};
@@ -32,6 +52,12 @@ enum Subcommand {
     Tunnel(Tunnel),
     /// Run a remote command using SSH
     Command(Command),
+    /// Manage multiplexed SSH master connections
+    Mux(Mux),
+    /// Manage pinned SSH host keys
+    Hostkeys(Hostkeys),
+    /// Check which of the configured hosts are reachable
+    Ping(Ping),
     /// Open config file
     Config,
 }
@@ -60,7 +86,7 @@ pub struct Rdp {
     edit: bool,
 }
 
-#[derive(Args)]
+#[derive(Args, Default)]
 pub struct SshCommon {
     /// Connect via IPv4 address
     #[clap(long)]
@@ -77,54 +103,196 @@ pub struct SshCommon {
     /// Print the command to stdout instead of connecting
     #[clap(long)]
     stdout: bool,
+    /// Check that the host is reachable and running SSH before connecting
+    #[clap(long)]
+    preflight: bool,
 }
 
 #[derive(Args)]
 pub struct Ssh {
     /// Name of the SSH profile to launch
     name: String,
+    /// Connect using mosh instead of ssh
+    #[clap(long)]
+    mosh: bool,
+    /// Attach to (or create) the named remote tmux/screen session
+    #[clap(long)]
+    session: Option<String>,
+    /// Don't attach to the profile's remote session
+    #[clap(long, conflicts_with = "session")]
+    no_session: bool,
+    /// List the existing remote tmux/screen sessions
+    #[clap(long, conflicts_with_all = &["session", "mosh"])]
+    list_sessions: bool,
     #[clap(flatten)]
     common: SshCommon,
 }
 
-#[derive(Args)]
+#[derive(Args, Default)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Tunnel {
+    #[clap(subcommand)]
+    action: Option<TunnelAction>,
     /// Name of the tunnel profile to launch
-    name: String,
+    #[clap(required = true)]
+    name: Option<String>,
+    /// Detach from the terminal and keep the tunnel open in the background
+    #[clap(long, conflicts_with = "stdout")]
+    background: bool,
+    /// Run a local command once the tunnel is ready, then close the tunnel
+    #[clap(long, requires = "command", conflicts_with_all = &["stdout", "background"])]
+    exec: bool,
+    /// The command for --exec, with `REMOTEC_PORT_<N>` (or `REMOTEC_PORT_<NAME>`) set to the
+    /// forwards' local ports
+    #[clap(last = true, requires = "exec")]
+    command: Vec<String>,
+    /// Write a systemd user unit that keeps the tunnel open while logged in
+    #[clap(long, conflicts_with_all = &["stdout", "background", "exec"])]
+    install_systemd: bool,
+    /// Also write a socket unit, so that the tunnel is started by the first connection to it
+    #[clap(long, requires = "install-systemd")]
+    socket: bool,
+    /// Stop and remove the tunnel's systemd user units
+    #[clap(long, conflicts_with_all = &["stdout", "background", "exec", "install-systemd"])]
+    uninstall_systemd: bool,
+    /// Print commands that set the running tunnel's environment variables (from the forwards'
+    /// `env` templates and `REMOTEC_PORT_<N>`), e.g. for `eval "$(remotec tunnel db --env)"`
+    #[clap(long, conflicts_with_all = &[
+        "stdout", "background", "exec", "install-systemd", "uninstall-systemd"
+    ])]
+    env: bool,
+    /// The shell to print the commands for
+    #[clap(long, value_enum, default_value = "bash", requires = "env")]
+    shell: Shell,
     #[clap(flatten)]
     common: SshCommon,
 }
 
-#[derive(Args)]
+#[derive(Parser)]
+pub enum TunnelAction {
+    /// List the running tunnels
+    List,
+    /// Print the local ports of a running tunnel
+    Ports {
+        /// Name of the tunnel profile
+        name: String,
+    },
+    /// Stop a tunnel running in the background
+    Stop {
+        /// Name of the tunnel profile
+        #[clap(required_unless_present = "all")]
+        name: Option<String>,
+        /// Stop all background tunnels
+        #[clap(long, conflicts_with = "name")]
+        all: bool,
+    },
+}
+
+#[derive(Args, Default)]
 pub struct Command {
     /// Name of the command to run
     name: String,
+    /// Values for the command's parameters, in the order they are declared
+    #[clap(value_name = "ARGS")]
+    args: Vec<String>,
+    /// Set one of the command's parameters
+    #[clap(long, value_name = "NAME=VALUE")]
+    set: Vec<String>,
+    /// The SSH profiles to run on instead of the command's: names, glob patterns (`web-*`) or
+    /// tags (`tag:web`)
+    #[clap(long, value_name = "PROFILES")]
+    on: Vec<String>,
+    /// The most hosts to run on at once, when running on several
+    #[clap(long, value_name = "N")]
+    parallel: Option<usize>,
     #[clap(flatten)]
     common: SshCommon,
 }
 
+#[derive(Args)]
+pub struct Mux {
+    #[clap(subcommand)]
+    action: MuxAction,
+}
+
+#[derive(Parser)]
+pub enum MuxAction {
+    /// Check whether a master connection is open
+    Status {
+        /// Name of the SSH profile
+        name: String,
+    },
+    /// Close the master connection
+    Stop {
+        /// Name of the SSH profile
+        name: String,
+    },
+}
+
+#[derive(Args)]
+pub struct Hostkeys {
+    #[clap(subcommand)]
+    action: HostkeysAction,
+}
+
+#[derive(Parser)]
+pub enum HostkeysAction {
+    /// Compare pinned host keys against your known_hosts file
+    Verify {
+        /// Name of the SSH profile (defaults to all profiles)
+        name: Option<String>,
+    },
+}
+
+#[derive(Args)]
+pub struct Ping {
+    /// Only check profiles with names matching this glob pattern
+    #[clap(long)]
+    name: Option<String>,
+    /// Only check these types of address
+    #[clap(long = "type", value_enum)]
+    kind: Vec<PingKind>,
+    /// Seconds to wait for each connection
+    #[clap(long, default_value = "3")]
+    timeout: u64,
+    /// Print the results as JSON
+    #[clap(long)]
+    json: bool,
+}
+
 fn main() {
+    if let Some(code) = askpass::askpass_main() {
+        std::process::exit(code);
+    }
     let args = Cli::parse();
     env_logger::Builder::from_env(Env::default().default_filter_or("info"))
         .target(Target::Stderr)
         .init();
-    if let Err(e) = run(args) {
-        log::error!("{:#}", e);
-        std::process::exit(1);
+    match run(args) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            log::error!("{:#}", e);
+            std::process::exit(exit::error_code(&e));
+        }
     }
 }
 
-fn run(args: Cli) -> anyhow::Result<()> {
-    let config = Config::load()?;
+/// Returns the exit code of the launched program, or 0 if nothing was waited on
+fn run(args: Cli) -> anyhow::Result<i32> {
+    let config = Config::load().context(Failure::Config)?;
     match args.subcommand {
-        Subcommand::Rdp(rdp) => launch_rdp(&config, &rdp),
+        Subcommand::Rdp(rdp) => launch_rdp(&config, &rdp).map(|_| 0),
         Subcommand::Ssh(ssh) => launch_ssh(&config, &ssh),
         Subcommand::Tunnel(tunnel) => launch_tunnel(&config, &tunnel),
         Subcommand::Command(cmd) => launch_command(&config, &cmd),
+        Subcommand::Mux(mux) => launch_mux(&config, &mux),
+        Subcommand::Hostkeys(hostkeys) => launch_hostkeys(&config, &hostkeys),
+        Subcommand::Ping(ping) => launch_ping(&config, &ping),
         Subcommand::Config => {
-            let cfg_path = config::config_path()?;
-            open::that(&cfg_path).context("Unable to open config file")
+            let cfg_path = config::config_path().context(Failure::Config)?;
+            open::that(&cfg_path)
+                .context("Unable to open config file")
+                .map(|_| 0)
         }
-    }?;
-    Ok(())
+    }
 }
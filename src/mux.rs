@@ -0,0 +1,101 @@
+use crate::config::SshProfile;
+use crate::runtime::{runtime_subdirectory, short_hash};
+use crate::select::select_profile_by_name;
+use crate::ssh::{invoke_ssh, ssh_target};
+use crate::{Config, Mux, MuxAction, SshCommon};
+use anyhow::bail;
+use std::path::PathBuf;
+
+const DEFAULT_PERSIST: &str = "10m";
+
+/// The control socket for a profile's master connection.
+///
+/// Unix socket paths are limited to around 104 bytes, so the socket is named after a hash of the
+/// profile name, which keeps the path the same length however long the name is.
+pub fn control_path(profile: &SshProfile) -> anyhow::Result<PathBuf> {
+    Ok(runtime_subdirectory("mux")?.join(short_hash(&profile.name)))
+}
+
+/// Options that make ssh share a master connection for this profile
+pub fn multiplex_args(profile: &SshProfile) -> anyhow::Result<Vec<String>> {
+    if !profile.multiplex {
+        return Ok(Vec::new());
+    }
+    if cfg!(windows) {
+        log::warn!("Connection multiplexing isn't supported by OpenSSH for Windows");
+        return Ok(Vec::new());
+    }
+    let persist = profile
+        .multiplex_persist
+        .as_deref()
+        .unwrap_or(DEFAULT_PERSIST);
+    Ok(vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}", control_path(profile)?.display()),
+        "-o".to_string(),
+        format!("ControlPersist={persist}"),
+    ])
+}
+
+pub fn launch_mux(config: &Config, cli: &Mux) -> anyhow::Result<i32> {
+    let (name, operation) = match &cli.action {
+        MuxAction::Status { name } => (name, "check"),
+        MuxAction::Stop { name } => (name, "exit"),
+    };
+    let profile = select_profile_by_name("SSH", &config.ssh, name, false)?;
+    if !profile.multiplex {
+        bail!("Multiplexing isn't enabled for profile `{name}`");
+    }
+    if !control_path(profile)?.exists() {
+        log::info!("No master connection is open for `{name}`");
+        return Ok(matches!(cli.action, MuxAction::Status { .. }) as i32);
+    }
+    let mut target = ssh_target(config, &SshCommon::default(), name, false)?;
+    target.options.insert(0, "-O".to_string());
+    target.options.insert(1, operation.to_string());
+    invoke_ssh(target, Vec::new(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::ssh_profile;
+    use serde_json::json;
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(multiplex_args(&ssh_profile(json!({}))).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn master_options() {
+        let profile = ssh_profile(json!({"multiplex": true, "multiplex_persist": "1h"}));
+        let path = control_path(&profile).unwrap();
+        assert_eq!(
+            multiplex_args(&profile).unwrap(),
+            [
+                "-o".to_string(),
+                "ControlMaster=auto".to_string(),
+                "-o".to_string(),
+                format!("ControlPath={}", path.display()),
+                "-o".to_string(),
+                "ControlPersist=1h".to_string(),
+            ]
+        );
+        let profile = ssh_profile(json!({"multiplex": true}));
+        assert_eq!(multiplex_args(&profile).unwrap()[5], "ControlPersist=10m");
+    }
+
+    #[test]
+    fn long_names_fit_in_a_socket_path() {
+        let short = control_path(&ssh_profile(json!({"name": "a"}))).unwrap();
+        let long = control_path(&ssh_profile(json!({"name": "a".repeat(200)}))).unwrap();
+        assert_ne!(short, long);
+        assert_eq!(short.as_os_str().len(), long.as_os_str().len());
+        // OpenSSH binds a temporary name with about 17 more characters before renaming it
+        assert!(long.as_os_str().len() + 17 < 104, "{}", long.display());
+    }
+}
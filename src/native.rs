@@ -0,0 +1,675 @@
+// An in-process SSH client for systems without the OpenSSH binary.
+//
+// It's built on libssh2 (through the ssh2 crate) rather than a pure Rust client such as russh,
+// because remotec is synchronous, and russh needs an async runtime (tokio) that nothing else here
+// would use. libssh2 also already understands the user's agent, OpenSSH private keys (including
+// encrypted ones) and known_hosts files. The cost is that it's a C library, which links OpenSSL
+// for its cryptography on Unix.
+
+use crate::askpass::run_password_command;
+use crate::config::{Forward, ForwardListen, ForwardTarget, SshForwardArgument};
+use crate::hostkeys::{fingerprint, matches_pins};
+use crate::ssh::{Hop, SshTarget};
+use crate::tunnel::listen_address;
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ssh2::{Channel, CheckResult, KnownHostFileKind, Session};
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const KEEPALIVE_INTERVAL: u32 = 30;
+/// The longest to wait for the sockets without checking the channels again. libssh2 may read the
+/// data for one channel while reading another, which then won't wake up a wait on the socket.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// A session to the destination, along with the threads relaying it through any jump hosts
+struct Connection {
+    session: Session,
+    _relays: Vec<JoinHandle<()>>,
+}
+
+/// Runs an interactive shell, or a remote command, and returns its exit code
+pub fn run(target: SshTarget, command: Vec<String>) -> anyhow::Result<i32> {
+    // As with OpenSSH, a terminal is only allocated if there's one to pass on
+    let tty = (target.tty || command.is_empty()) && io::stdin().is_terminal();
+    let connection = connect(&target)?;
+    let session = &connection.session;
+
+    let mut channel = session
+        .channel_session()
+        .context("Unable to open session")?;
+    if tty {
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+        let (width, height) = terminal::size();
+        channel
+            .request_pty(&term, None, Some((width, height, 0, 0)))
+            .context("Unable to allocate a terminal")?;
+    }
+    if command.is_empty() {
+        channel.shell().context("Unable to start shell")?;
+    } else {
+        // The words are joined for the remote shell to parse, as OpenSSH does, so callers have
+        // already quoted any that are meant literally (see `session::remote_command`)
+        channel
+            .exec(&command.join(" "))
+            .context("Unable to run command")?;
+    }
+    let _raw = tty.then(terminal::RawMode::enable).transpose()?;
+
+    let mut input = stdin_stream()?;
+    input.set_nonblocking(true)?;
+    session.set_blocking(false);
+    let mut stdout = io::stdout();
+    let mut stderr = io::stderr();
+    let mut to_remote = Flow::default();
+    let mut buf = [0; 16384];
+    while !channel.eof() {
+        let mut progress = false;
+        if let Some(n) = would_block(channel.read(&mut buf))? {
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+            progress |= n > 0;
+        }
+        if let Some(n) = would_block(channel.stderr().read(&mut buf))? {
+            stderr.write_all(&buf[..n])?;
+            progress |= n > 0;
+        }
+        progress |= to_remote.poll(&mut input, &mut channel)?;
+        if !progress {
+            let mut wait = Wait::default();
+            wait.session(session);
+            if to_remote.buf.is_empty() && !to_remote.eof {
+                wait.read(&input);
+            }
+            wait.until(MAX_WAIT);
+        }
+    }
+    session.set_blocking(true);
+    io::copy(&mut channel, &mut stdout)?;
+    io::copy(&mut channel.stderr(), &mut stderr)?;
+    channel.wait_close().context("Error closing channel")?;
+    channel.exit_status().context("Unable to get exit status")
+}
+
+/// Listens on the local ports, forwarding connections to the remote hosts until interrupted.
+///
+/// `ready` is called once all of the ports are listening.
+pub fn forward(
+    target: SshTarget,
+    forwards: &[SshForwardArgument],
+    ready: impl FnOnce(),
+) -> anyhow::Result<i32> {
+    // Only local TCP forwards are implemented here
+    let forwards = forwards
+        .iter()
+        .map(|f| match &f.forward {
+            Forward::Local {
+                listen: ForwardListen::Port { bind_address, port },
+                target:
+                    ForwardTarget::Host {
+                        host,
+                        port: remote_port,
+                    },
+            } => Ok((bind_address.as_deref(), *port, host.as_str(), *remote_port)),
+            _ => bail!(
+                "The native SSH backend only supports local TCP forwards, not {}",
+                f.description()
+            ),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let connection = connect(&target)?;
+    let session = &connection.session;
+
+    let listeners = forwards
+        .into_iter()
+        .map(|(bind_address, port, host, remote_port)| {
+            let listener = TcpListener::bind((listen_address(bind_address), port))
+                .with_context(|| format!("Unable to listen on local port {port}"))?;
+            listener.set_nonblocking(true)?;
+            Ok((listener, host, remote_port))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    ready();
+
+    session.set_blocking(false);
+    let mut pipes: Vec<Pipe<TcpStream, Channel>> = Vec::new();
+    let mut last_keepalive = Instant::now();
+    loop {
+        let mut progress = false;
+        for (listener, host, port) in &listeners {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e).context("Unable to accept connection"),
+            };
+            progress = true;
+            session.set_blocking(true);
+            let channel = session.channel_direct_tcpip(host, *port, None);
+            session.set_blocking(false);
+            match channel {
+                Ok(channel) => {
+                    stream.set_nonblocking(true)?;
+                    pipes.push(Pipe::new(stream, channel));
+                }
+                Err(e) => log::warn!("Unable to forward to {host}:{port}: {e}"),
+            }
+        }
+        pipes.retain_mut(|pipe| match pipe.poll() {
+            Ok(p) => {
+                progress |= p;
+                !pipe.done()
+            }
+            Err(e) => {
+                log::debug!("Forwarded connection closed: {e}");
+                false
+            }
+        });
+        let keepalive = Duration::from_secs(KEEPALIVE_INTERVAL as u64);
+        if last_keepalive.elapsed() > keepalive {
+            session.set_blocking(true);
+            session.keepalive_send().context("Connection lost")?;
+            session.set_blocking(false);
+            last_keepalive = Instant::now();
+        }
+        if !progress {
+            let mut wait = Wait::default();
+            wait.session(session);
+            for (listener, ..) in &listeners {
+                wait.read(listener);
+            }
+            for pipe in &pipes {
+                pipe.wait(&mut wait);
+            }
+            wait.until(MAX_WAIT.min(keepalive.saturating_sub(last_keepalive.elapsed())));
+        }
+    }
+}
+
+/// Connects to the destination, tunnelling through each jump host in turn
+fn connect(target: &SshTarget) -> anyhow::Result<Connection> {
+    let password_command = target.profile.password_command.as_deref();
+    let first = &target.hops[0];
+    let mut stream = TcpStream::connect((first.hostname, first.port()))
+        .with_context(|| format!("Unable to connect to {}:{}", first.hostname, first.port()))?;
+    let mut relays = Vec::new();
+    for (idx, hop) in target.hops.iter().enumerate() {
+        let session = handshake(stream, hop, password_command)?;
+        let Some(next) = target.hops.get(idx + 1) else {
+            return Ok(Connection {
+                session,
+                _relays: relays,
+            });
+        };
+        log::info!("Jumping to {} via {}", next.hostname, hop.hostname);
+        let channel = session
+            .channel_direct_tcpip(next.hostname, next.port(), None)
+            .with_context(|| format!("{} refused to forward to the next host", hop.hostname))?;
+        let (local, relay) = relay(session, channel)?;
+        stream = local;
+        relays.push(relay);
+    }
+    unreachable!("There is always at least one hop")
+}
+
+fn handshake(
+    stream: TcpStream,
+    hop: &Hop,
+    password_command: Option<&[String]>,
+) -> anyhow::Result<Session> {
+    let mut session = Session::new().context("Unable to create SSH session")?;
+    session.set_tcp_stream(stream);
+    session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {} failed", hop.hostname))?;
+    session.set_keepalive(true, KEEPALIVE_INTERVAL);
+    verify_host_key(&session, hop)?;
+    authenticate(&session, hop, password_command)?;
+    Ok(session)
+}
+
+fn verify_host_key(session: &Session, hop: &Hop) -> anyhow::Result<()> {
+    let (key, _) = session
+        .host_key()
+        .context("Server didn't send a host key")?;
+    let blob = STANDARD.encode(key);
+    let fp = fingerprint(&blob)?;
+    if !hop.host_keys.is_empty() {
+        if matches_pins(hop.host_keys, &blob)? {
+            return Ok(());
+        }
+        bail!(
+            "Host key for {} ({fp}) doesn't match its pinned keys",
+            hop.hostname
+        );
+    }
+    let mut known_hosts = session.known_hosts()?;
+    let path = dirs::home_dir()
+        .context("Unable to get home directory")?
+        .join(".ssh")
+        .join("known_hosts");
+    if path.exists() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .context("Unable to read known_hosts")?;
+    }
+    match known_hosts.check_port(hop.hostname, hop.port(), key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => bail!(
+            "Host key for {} ({fp}) isn't in your known_hosts, pin it in the profile",
+            hop.hostname
+        ),
+        CheckResult::Mismatch => bail!(
+            "HOST KEY MISMATCH for {} ({fp}), someone could be eavesdropping",
+            hop.hostname
+        ),
+        CheckResult::Failure => bail!("Unable to check host key for {}", hop.hostname),
+    }
+}
+
+fn authenticate(
+    session: &Session,
+    hop: &Hop,
+    password_command: Option<&[String]>,
+) -> anyhow::Result<()> {
+    let username = &hop.username;
+    if session.userauth_agent(username).is_ok() {
+        return Ok(());
+    }
+    let mut password = None;
+    if let Some(ssh_dir) = dirs::home_dir().map(|h| h.join(".ssh")) {
+        for key in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+            let path = ssh_dir.join(key);
+            if !path.exists() {
+                continue;
+            }
+            if session
+                .userauth_pubkey_file(username, None, &path, None)
+                .is_ok()
+            {
+                return Ok(());
+            }
+            // It might be encrypted, in which case the password command gives the passphrase
+            if let Some(command) = password_command {
+                let passphrase = password.get_or_insert(run_password_command(command)?);
+                if session
+                    .userauth_pubkey_file(username, None, &path, Some(passphrase))
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    let password = match (password, password_command) {
+        (Some(password), _) => password,
+        (None, Some(command)) => run_password_command(command)?,
+        (None, None) => {
+            terminal::read_password(&format!("{username}@{}'s password: ", hop.hostname))?
+        }
+    };
+    session
+        .userauth_password(username, &password)
+        .with_context(|| format!("Authentication failed for {username}@{}", hop.hostname))
+}
+
+/// A connected pair of loopback TCP streams
+fn loopback_pair() -> anyhow::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).context("Unable to create relay")?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let (remote, peer) = listener.accept()?;
+    if peer != local.local_addr()? {
+        bail!("Unexpected connection to relay from {peer}");
+    }
+    Ok((local, remote))
+}
+
+/// Relays a channel through a loopback TCP connection, so another session can run over it
+fn relay(session: Session, channel: Channel) -> anyhow::Result<(TcpStream, JoinHandle<()>)> {
+    let (local, remote) = loopback_pair()?;
+    remote.set_nonblocking(true)?;
+    session.set_blocking(false);
+    let handle = std::thread::spawn(move || {
+        let mut pipe = Pipe::new(remote, channel);
+        while !pipe.done() {
+            match pipe.poll() {
+                Ok(true) => {}
+                Ok(false) => {
+                    let mut wait = Wait::default();
+                    wait.session(&session);
+                    pipe.wait(&mut wait);
+                    wait.until(MAX_WAIT);
+                }
+                Err(e) => {
+                    log::debug!("Relay closed: {e}");
+                    break;
+                }
+            }
+        }
+    });
+    Ok((local, handle))
+}
+
+/// Copies stdin to a loopback connection on another thread, because stdin can't be read without
+/// blocking (or waited on along with the session) on every platform
+fn stdin_stream() -> anyhow::Result<TcpStream> {
+    let (mut writer, reader) = loopback_pair()?;
+    std::thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin(), &mut writer);
+        let _ = writer.shutdown(Shutdown::Write);
+    });
+    Ok(reader)
+}
+
+/// The sockets for an event loop to wait on when none of them are ready, instead of repeatedly
+/// polling them
+#[derive(Default)]
+struct Wait {
+    #[cfg(unix)]
+    fds: Vec<libc::pollfd>,
+}
+
+#[cfg(unix)]
+impl Wait {
+    /// The session's socket, which is waited on for new data, and for writing if libssh2 is
+    /// blocked on sending
+    fn session(&mut self, session: &Session) {
+        use ssh2::BlockDirections;
+        let mut events = libc::POLLIN;
+        if matches!(
+            session.block_directions(),
+            BlockDirections::Outbound | BlockDirections::Both
+        ) {
+            events |= libc::POLLOUT;
+        }
+        self.add(session, events);
+    }
+
+    fn read(&mut self, socket: &impl std::os::unix::io::AsRawFd) {
+        self.add(socket, libc::POLLIN);
+    }
+
+    fn write(&mut self, socket: &impl std::os::unix::io::AsRawFd) {
+        self.add(socket, libc::POLLOUT);
+    }
+
+    fn add(&mut self, socket: &impl std::os::unix::io::AsRawFd, events: libc::c_short) {
+        self.fds.push(libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events,
+            revents: 0,
+        });
+    }
+
+    /// Waits until any of the sockets are ready, or the timeout passes
+    fn until(mut self, timeout: Duration) {
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+        unsafe { libc::poll(self.fds.as_mut_ptr(), self.fds.len() as _, timeout) };
+    }
+}
+
+/// Without `poll`, waiting just sleeps for a short time
+#[cfg(not(unix))]
+impl Wait {
+    fn session(&mut self, _session: &Session) {}
+
+    fn read<T>(&mut self, _socket: &T) {}
+
+    fn write<T>(&mut self, _socket: &T) {}
+
+    fn until(self, timeout: Duration) {
+        std::thread::sleep(timeout.min(Duration::from_millis(5)));
+    }
+}
+
+/// Maps a non-blocking `WouldBlock` result to `None`
+fn would_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A stream that can signal that it won't write any more data
+trait HalfClose: Read + Write {
+    fn close_write(&mut self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn close_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl HalfClose for Channel {
+    fn close_write(&mut self) -> io::Result<()> {
+        self.send_eof().map_err(io::Error::from)
+    }
+}
+
+/// One direction of a pipe
+#[derive(Default)]
+struct Flow {
+    buf: Vec<u8>,
+    eof: bool,
+    closed: bool,
+}
+
+impl Flow {
+    fn poll(&mut self, from: &mut impl Read, to: &mut impl HalfClose) -> io::Result<bool> {
+        let mut progress = false;
+        if self.buf.is_empty() && !self.eof {
+            let mut buf = [0; 16384];
+            if let Some(n) = would_block(from.read(&mut buf))? {
+                self.eof = n == 0;
+                self.buf.extend_from_slice(&buf[..n]);
+                progress = true;
+            }
+        }
+        if !self.buf.is_empty() {
+            if let Some(n) = would_block(to.write(&self.buf))? {
+                self.buf.drain(..n);
+                progress |= n > 0;
+            }
+        } else if self.eof && !self.closed {
+            self.closed = would_block(to.close_write())?.is_some();
+            progress |= self.closed;
+        }
+        Ok(progress)
+    }
+}
+
+/// Copies data in both directions between two non-blocking streams
+struct Pipe<A, B> {
+    a: A,
+    b: B,
+    a_to_b: Flow,
+    b_to_a: Flow,
+}
+
+impl<A: HalfClose, B: HalfClose> Pipe<A, B> {
+    fn new(a: A, b: B) -> Self {
+        Self {
+            a,
+            b,
+            a_to_b: Flow::default(),
+            b_to_a: Flow::default(),
+        }
+    }
+
+    /// Copies any available data, returning whether anything happened
+    fn poll(&mut self) -> io::Result<bool> {
+        let forward = self.a_to_b.poll(&mut self.a, &mut self.b)?;
+        let backward = self.b_to_a.poll(&mut self.b, &mut self.a)?;
+        Ok(forward || backward)
+    }
+
+    fn done(&self) -> bool {
+        self.a_to_b.closed && self.b_to_a.closed
+    }
+}
+
+impl<B> Pipe<TcpStream, B> {
+    /// Adds the local stream to wait on (the channel is waited on through its session)
+    fn wait(&self, wait: &mut Wait) {
+        if self.a_to_b.buf.is_empty() && !self.a_to_b.eof {
+            wait.read(&self.a);
+        }
+        if !self.b_to_a.buf.is_empty() {
+            wait.write(&self.a);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod terminal {
+    use anyhow::Context;
+    use std::io::{BufRead, BufReader, Write};
+
+    pub fn size() -> (u32, u32) {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 {
+            return (size.ws_col as u32, size.ws_row as u32);
+        }
+        (80, 24)
+    }
+
+    /// Puts the local terminal into raw mode, restoring it when dropped
+    pub struct RawMode(libc::termios);
+
+    impl RawMode {
+        pub fn enable() -> anyhow::Result<Self> {
+            let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("Unable to get terminal");
+            }
+            let original = termios;
+            unsafe { libc::cfmakeraw(&mut termios) };
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("Unable to set terminal");
+            }
+            Ok(Self(original))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+        }
+    }
+
+    pub fn read_password(prompt: &str) -> anyhow::Result<String> {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .context("Unable to open terminal")?;
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&tty);
+        unsafe { libc::tcgetattr(fd, &mut termios) };
+        let original = termios;
+        termios.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+        let mut writer = &tty;
+        write!(writer, "{prompt}")?;
+        let mut line = String::new();
+        let result = BufReader::new(&tty).read_line(&mut line);
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+        writeln!(writer)?;
+        result.context("Unable to read password")?;
+        Ok(line.trim_end().to_string())
+    }
+}
+
+#[cfg(not(unix))]
+mod terminal {
+    use anyhow::bail;
+
+    pub fn size() -> (u32, u32) {
+        (80, 24)
+    }
+
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enable() -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+    }
+
+    pub fn read_password(_prompt: &str) -> anyhow::Result<String> {
+        bail!("Unable to prompt for a password, set a password_command for the profile")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_copies_both_ways() {
+        let (mut a, a_end) = loopback_pair().unwrap();
+        let (mut b, b_end) = loopback_pair().unwrap();
+        a_end.set_nonblocking(true).unwrap();
+        b_end.set_nonblocking(true).unwrap();
+        a.write_all(b"from a").unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+        b.write_all(b"from b").unwrap();
+        b.shutdown(Shutdown::Write).unwrap();
+
+        let mut pipe = Pipe::new(a_end, b_end);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !pipe.done() {
+            assert!(Instant::now() < deadline, "pipe didn't finish");
+            if !pipe.poll().unwrap() {
+                let mut wait = Wait::default();
+                pipe.wait(&mut wait);
+                wait.until(MAX_WAIT);
+            }
+        }
+        drop(pipe);
+        let mut received = String::new();
+        a.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "from b");
+        received.clear();
+        b.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "from a");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_wakes_on_data() {
+        let (mut writer, reader) = loopback_pair().unwrap();
+        let start = Instant::now();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            writer.write_all(b"x").unwrap();
+        });
+        let mut wait = Wait::default();
+        wait.read(&reader);
+        wait.until(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn handshake_with_non_ssh_server() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        });
+        let hop = Hop {
+            username: "test".to_string(),
+            hostname: "127.0.0.1",
+            port: Some(port),
+            host_keys: &[],
+        };
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let error = handshake(stream, &hop, None).err().unwrap();
+        assert!(format!("{error:#}").contains("handshake"), "{error:#}");
+    }
+}
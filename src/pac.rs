@@ -0,0 +1,140 @@
+//! Serves a proxy auto-config (PAC) file that sends a tunnel profile's `proxy_domains` through
+//! its SOCKS forward, and everything else direct
+
+use crate::background::record_pac;
+use crate::config::{Forward, SshForwardArgument, TunnelProfile};
+use crate::tunnel::{host_port, listen_address};
+use anyhow::{bail, Context};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread::spawn;
+use std::time::Duration;
+
+const PAC_PATH: &str = "/proxy.pac";
+/// How long to wait for a browser to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts serving the PAC file if the profile has `proxy_domains`, returning its URL. It's served
+/// until remotec exits.
+pub fn serve_pac(
+    profile: &TunnelProfile,
+    forwards: &[SshForwardArgument],
+) -> anyhow::Result<Option<String>> {
+    if profile.proxy_domains.is_empty() {
+        return Ok(None);
+    }
+    let proxy = forwards
+        .iter()
+        .find_map(|f| match &f.forward {
+            Forward::Dynamic { bind_address, port } => {
+                let address = match listen_address(bind_address.as_deref()) {
+                    "0.0.0.0" => "127.0.0.1",
+                    "::" => "::1",
+                    address => address,
+                };
+                Some(host_port(address, *port))
+            }
+            _ => None,
+        })
+        .context("`proxy_domains` needs a dynamic (SOCKS) forward")?;
+    if proxy.ends_with(":0") {
+        bail!("The SOCKS forward's local port hasn't been chosen");
+    }
+    let pac = render_pac(&profile.proxy_domains, &proxy);
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, profile.pac_port.unwrap_or(0)))
+        .context("Unable to listen for the PAC file server")?;
+    let url = format!(
+        "http://127.0.0.1:{}{PAC_PATH}",
+        listener
+            .local_addr()
+            .context("Unable to get PAC file port")?
+            .port()
+    );
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = respond(stream, &pac) {
+                log::debug!("Unable to serve PAC file: {e}");
+            }
+        }
+    });
+    log::info!("Serving PAC file at {url}");
+    if let Err(e) = record_pac(profile, &url) {
+        log::warn!("Unable to record the PAC file URL: {e:#}");
+    }
+    Ok(Some(url))
+}
+
+/// Answers any request with the PAC file
+fn respond(mut stream: TcpStream, pac: &str) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let head = request.starts_with(b"HEAD ");
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/x-ns-proxy-autoconfig\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n",
+        pac.len()
+    )?;
+    if !head {
+        stream.write_all(pac.as_bytes())?;
+    }
+    stream.flush()
+}
+
+/// A PAC file that uses the proxy for the domains. A pattern without a `*` also matches its
+/// subdomains.
+fn render_pac(domains: &[String], proxy: &str) -> String {
+    let conditions = domains
+        .iter()
+        .flat_map(|d| {
+            let mut patterns = vec![d.clone()];
+            if !d.contains('*') {
+                patterns.push(format!("*.{d}"));
+            }
+            patterns
+        })
+        .map(|p| format!("shExpMatch(host, {})", serde_json::to_string(&p).unwrap()))
+        .collect::<Vec<_>>();
+    format!(
+        "function FindProxyForURL(url, host) {{\n\
+        \x20   if ({}) {{\n\
+        \x20       return \"SOCKS5 {proxy}; SOCKS {proxy}\";\n\
+        \x20   }}\n\
+        \x20   return \"DIRECT\";\n\
+        }}\n",
+        conditions.join(" ||\n        ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxied_domains() {
+        let domains = ["corp.example".to_string(), "*.internal".to_string()];
+        assert_eq!(
+            render_pac(&domains, "127.0.0.1:1080"),
+            "function FindProxyForURL(url, host) {\n\
+            \x20   if (shExpMatch(host, \"corp.example\") ||\n\
+            \x20       shExpMatch(host, \"*.corp.example\") ||\n\
+            \x20       shExpMatch(host, \"*.internal\")) {\n\
+            \x20       return \"SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080\";\n\
+            \x20   }\n\
+            \x20   return \"DIRECT\";\n\
+            }\n"
+        );
+    }
+}
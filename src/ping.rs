@@ -0,0 +1,298 @@
+use crate::address::resolve;
+use crate::config::{Address, Forward, ForwardTarget, TunnelTransport};
+use crate::{Config, Ping};
+use anyhow::Context;
+use clap::ValueEnum;
+use glob::Pattern;
+use serde::Serialize;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const RDP_PORT: u16 = 3389;
+const SSH_PORT: u16 = 22;
+
+#[derive(ValueEnum, Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PingKind {
+    Rdp,
+    Ssh,
+    Jump,
+    /// A tunnel's target, which is connected to from the SSH host rather than from here. Probing
+    /// it only shows whether it's reachable locally (e.g. over the VPN).
+    Tunnel,
+}
+
+/// An address to probe
+struct Probe<'a> {
+    profile: &'a str,
+    kind: PingKind,
+    host: &'a str,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct PingResult<'a> {
+    profile: &'a str,
+    #[serde(rename = "type")]
+    kind: PingKind,
+    address: String,
+    family: Option<&'static str>,
+    latency_ms: Option<u128>,
+    status: String,
+}
+
+pub fn launch_ping(config: &Config, cli: &Ping) -> anyhow::Result<i32> {
+    let results = ping(config, cli)?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&results).unwrap());
+    } else {
+        print_table(&results);
+        if results.iter().any(|r| r.kind == PingKind::Tunnel) {
+            println!("\nTunnel targets are checked from here, not from the tunnel's SSH host");
+        }
+    }
+    Ok(exit_code(&results))
+}
+
+/// Probes the addresses of the selected profiles, all at once
+fn ping<'a>(config: &'a Config, cli: &Ping) -> anyhow::Result<Vec<PingResult<'a>>> {
+    let pattern = cli
+        .name
+        .as_deref()
+        .map(Pattern::new)
+        .transpose()
+        .context("Invalid profile name pattern")?;
+    let included = |name: &str, kind: PingKind| {
+        pattern.as_ref().map(|p| p.matches(name)).unwrap_or(true)
+            && (cli.kind.is_empty() || cli.kind.contains(&kind))
+    };
+
+    let mut probes = Vec::new();
+    for profile in &config.rdp {
+        if included(&profile.name, PingKind::Rdp) {
+            probes.extend(address_probes(
+                &profile.name,
+                PingKind::Rdp,
+                &profile.address,
+                RDP_PORT,
+            ));
+        }
+    }
+    for profile in &config.ssh {
+        if included(&profile.name, PingKind::Ssh) {
+            probes.extend(address_probes(
+                &profile.name,
+                PingKind::Ssh,
+                &profile.address,
+                SSH_PORT,
+            ));
+        }
+        if included(&profile.name, PingKind::Jump) {
+            probes.extend(profile.jump_hosts.iter().map(|j| Probe {
+                profile: &profile.name,
+                kind: PingKind::Jump,
+                host: &j.hostname,
+                port: j.port.unwrap_or(SSH_PORT),
+            }));
+        }
+    }
+    for profile in &config.tunnels {
+        // kubectl forwards connect from within the cluster, which can't be probed from here
+        if included(&profile.name, PingKind::Tunnel) && profile.transport == TunnelTransport::Ssh {
+            // Only local forwards connect from the remote host to a known address
+            probes.extend(profile.forwards.iter().filter_map(|f| match &f.forward {
+                Forward::Local {
+                    target: ForwardTarget::Host { host, port },
+                    ..
+                } => Some(Probe {
+                    profile: &profile.name,
+                    kind: PingKind::Tunnel,
+                    host,
+                    port: *port,
+                }),
+                _ => None,
+            }));
+        }
+    }
+
+    let timeout = Duration::from_secs(cli.timeout);
+    let results = std::thread::scope(|scope| {
+        let handles = probes
+            .iter()
+            .map(|p| scope.spawn(move || probe(p, timeout)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    Ok(results)
+}
+
+/// 1 if any of the addresses couldn't be reached
+fn exit_code(results: &[PingResult]) -> i32 {
+    let failed = results.iter().any(|r| r.latency_ms.is_none());
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+/// Probes for each of the addresses configured for a profile
+fn address_probes<'a>(
+    profile: &'a str,
+    kind: PingKind,
+    address: &'a Address,
+    default_port: u16,
+) -> Vec<Probe<'a>> {
+    [&address.hostname, &address.ipv4, &address.ipv6]
+        .into_iter()
+        .flatten()
+        .map(|host| Probe {
+            profile,
+            kind,
+            host,
+            port: address.port.unwrap_or(default_port),
+        })
+        .collect()
+}
+
+fn probe<'a>(probe: &Probe<'a>, timeout: Duration) -> PingResult<'a> {
+    let mut result = PingResult {
+        profile: probe.profile,
+        kind: probe.kind,
+        address: format!("{}:{}", probe.host, probe.port),
+        family: None,
+        latency_ms: None,
+        status: String::new(),
+    };
+    let addresses = match resolve(probe.host, probe.port, timeout) {
+        Some(Ok(a)) => a,
+        Some(Err(_)) => {
+            result.status = "unresolved".to_string();
+            return result;
+        }
+        None => {
+            result.status = "dns timeout".to_string();
+            return result;
+        }
+    };
+    for address in addresses {
+        result.family = Some(family(&address));
+        let start = Instant::now();
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(_) => {
+                result.latency_ms = Some(start.elapsed().as_millis());
+                result.status = "ok".to_string();
+                return result;
+            }
+            Err(e) => {
+                result.status = match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => "refused".to_string(),
+                    std::io::ErrorKind::TimedOut => "timeout".to_string(),
+                    _ => e.to_string(),
+                }
+            }
+        }
+    }
+    result
+}
+
+fn family(address: &SocketAddr) -> &'static str {
+    match address {
+        SocketAddr::V4(_) => "IPv4",
+        SocketAddr::V6(_) => "IPv6",
+    }
+}
+
+fn print_table(results: &[PingResult]) {
+    let rows = results
+        .iter()
+        .map(|r| {
+            [
+                r.profile.to_string(),
+                r.kind.to_possible_value().unwrap().get_name().to_string(),
+                r.address.clone(),
+                r.family.unwrap_or("-").to_string(),
+                r.latency_ms
+                    .map(|l| format!("{l} ms"))
+                    .unwrap_or_else(|| "-".to_string()),
+                r.status.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["PROFILE", "TYPE", "ADDRESS", "FAMILY", "LATENCY", "STATUS"].map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::{config, ssh_json};
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    fn cli(name: &str) -> Ping {
+        Ping {
+            name: Some(name.to_string()),
+            kind: vec![PingKind::Ssh],
+            timeout: 1,
+            json: true,
+        }
+    }
+
+    #[test]
+    fn results() {
+        let open = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let open = open.local_addr().unwrap().port();
+        let closed = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let config = config(json!({"ssh": [
+            ssh_json(json!({"name": "up", "hostname": "127.0.0.1", "port": open})),
+            ssh_json(json!({"name": "down", "hostname": "127.0.0.1", "port": closed_port})),
+        ]}));
+
+        let results = ping(&config, &cli("*")).unwrap();
+        let json = serde_json::to_string(&results).unwrap();
+        let mut json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        json[0]["latency_ms"] = json!(0);
+        assert_eq!(
+            json,
+            json!([
+                {
+                    "profile": "up",
+                    "type": "ssh",
+                    "address": format!("127.0.0.1:{open}"),
+                    "family": "IPv4",
+                    "latency_ms": 0,
+                    "status": "ok"
+                },
+                {
+                    "profile": "down",
+                    "type": "ssh",
+                    "address": format!("127.0.0.1:{closed_port}"),
+                    "family": "IPv4",
+                    "latency_ms": null,
+                    "status": "refused"
+                }
+            ])
+        );
+        assert_eq!(exit_code(&results), 1);
+        assert_eq!(exit_code(&ping(&config, &cli("up")).unwrap()), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::address::resolve;
+use anyhow::{bail, Context};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Servers may send other lines before their identification string (RFC 4253 section 4.2)
+const MAX_PRE_BANNER_LINES: usize = 20;
+
+/// Connects to the host and reads its SSH identification string, e.g. `SSH-2.0-OpenSSH_9.0`,
+/// so that unreachable hosts give a clear error instead of hanging inside ssh
+pub fn check_banner(host: &str, port: u16) -> anyhow::Result<String> {
+    banner_with_timeout(host, port, TIMEOUT)
+}
+
+fn banner_with_timeout(host: &str, port: u16, timeout: Duration) -> anyhow::Result<String> {
+    let addresses = match resolve(host, port, timeout) {
+        Some(addresses) => addresses.with_context(|| format!("Unable to resolve {host}"))?,
+        None => bail!("Timed out resolving {host}, are you on the VPN?"),
+    };
+    let mut error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return read_banner(stream, host, port, timeout),
+            Err(e) => error = Some(e),
+        }
+    }
+    match error {
+        Some(e) => Err(connect_error(e, host, port)),
+        None => bail!("No addresses found for {host}"),
+    }
+}
+
+/// Reads the banner once connected, where a server that stays silent or closes the connection is
+/// reachable but isn't running SSH
+fn read_banner(
+    stream: TcpStream,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(stream);
+    for _ in 0..MAX_PRE_BANNER_LINES {
+        let mut line = Vec::new();
+        match reader.by_ref().take(256).read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => break,
+                _ => return Err(connect_error(e, host, port)),
+            },
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if line.starts_with("SSH-") {
+            return Ok(line);
+        }
+        if line.starts_with("HTTP/") || line.contains('\0') {
+            break;
+        }
+    }
+    bail!("{host}:{port} is not an SSH server (no banner)")
+}
+
+fn connect_error(error: io::Error, host: &str, port: u16) -> anyhow::Error {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => {
+            anyhow::anyhow!("Connection refused by {host}:{port}")
+        }
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            anyhow::anyhow!("Timed out connecting to {host}:{port}, are you on the VPN?")
+        }
+        _ => anyhow::Error::new(error).context(format!("Unable to connect to {host}:{port}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    const TEST_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Starts a server on a local port that sends `response` to the first connection
+    fn server(response: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(response);
+            // Hold the connection open for longer than the timeout
+            std::thread::sleep(TEST_TIMEOUT * 5);
+        });
+        port
+    }
+
+    #[test]
+    fn valid_banner() {
+        let port = server(b"Welcome\r\nSSH-2.0-OpenSSH_9.6\r\n");
+        let banner = banner_with_timeout("127.0.0.1", port, TEST_TIMEOUT).unwrap();
+        assert_eq!(banner, "SSH-2.0-OpenSSH_9.6");
+    }
+
+    #[test]
+    fn non_ssh_banner() {
+        let port = server(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        let error = banner_with_timeout("127.0.0.1", port, TEST_TIMEOUT).unwrap_err();
+        assert!(error.to_string().contains("not an SSH server"), "{error}");
+    }
+
+    #[test]
+    fn silent_server() {
+        let port = server(b"");
+        let error = banner_with_timeout("127.0.0.1", port, TEST_TIMEOUT).unwrap_err();
+        assert!(
+            error.to_string().contains("not an SSH server (no banner)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn refused_connection() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let error = banner_with_timeout("127.0.0.1", port, TEST_TIMEOUT).unwrap_err();
+        assert!(error.to_string().contains("Connection refused"), "{error}");
+    }
+}
@@ -1,13 +1,16 @@
 // See: https://docs.microsoft.com/en-us/windows-server/remote/remote-desktop-services/clients/rdp-files
 
+use crate::askpass::run_password_command;
 use crate::config::{GatewayPolicy, RdpBackend, RdpProfile};
+use crate::exit::launch_error;
 use crate::select::select_profile_by_name;
 use crate::{Config, Rdp};
 use anyhow::{bail, Context};
 use std::fmt::Write;
 use std::fs;
+use std::io::Write as _;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 fn cache_directory() -> anyhow::Result<PathBuf> {
     let dir = dirs::cache_dir()
@@ -45,7 +48,7 @@ pub fn launch_rdp(config: &Config, cli: &Rdp) -> anyhow::Result<()> {
     rdp_config.push("gatewayprofileusagemethod:i:1".to_string());
     rdp_config.push(format!(
         "promptcredentialonce:i:{}",
-        profile.separate_credentials.then_some(0).unwrap_or(1)
+        if profile.separate_credentials { 0 } else { 1 }
     ));
     rdp_config.push("".to_string());
 
@@ -62,7 +65,11 @@ pub fn launch_rdp(config: &Config, cli: &Rdp) -> anyhow::Result<()> {
         let dest = cache_directory()?.join(&profile.name).with_extension("rdp");
         fs::write(&dest, &rdp_config).context("Unable to write RDP config")?;
 
-        backend.open(&dest, cli.edit)?
+        let password = match &profile.password_command {
+            Some(command) if !cli.edit => Some(run_password_command(command)?),
+            _ => None,
+        };
+        backend.open(&dest, cli.edit, password)?
     }
 
     Ok(())
@@ -91,32 +98,128 @@ fn username(profile: &RdpProfile, config: &Config) -> String {
     whoami::username()
 }
 
+/// The X11 clients of FreeRDP, newest first. Distributions that package FreeRDP 3 alongside 2 name
+/// its client `xfreerdp3`.
+const FREERDP_PROGRAMS: [&str; 2] = ["xfreerdp3", "xfreerdp"];
+
+/// The first of `programs` that's installed, along with its major version
+fn find_freerdp<'a>(programs: &[&'a str]) -> anyhow::Result<(&'a str, u32)> {
+    for program in programs {
+        let output = match Command::new(program)
+            .arg("--version")
+            .stderr(Stdio::null())
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(launch_error(e, program)),
+        };
+        let output = String::from_utf8_lossy(&output.stdout);
+        let version = parse_freerdp_version(&output)
+            .with_context(|| format!("Unable to find FreeRDP version in `{}`", output.trim()))?;
+        return Ok((program, version));
+    }
+    let programs = programs.join(" or ");
+    Err(launch_error(std::io::ErrorKind::NotFound.into(), &programs))
+}
+
+/// Parses output such as `This is FreeRDP version 3.5.1 (n/a)`
+fn parse_freerdp_version(output: &str) -> Option<u32> {
+    let version = output.split("version ").nth(1)?;
+    version.split('.').next()?.trim().parse().ok()
+}
+
 impl RdpBackend {
     fn default_for_platform() -> anyhow::Result<Self> {
         cfg_if::cfg_if! {
             if #[cfg(windows)] {
                 Ok(RdpBackend::Mstsc)
             } else {
-                bail!("No RDP backend supported for this platform")
+                Ok(RdpBackend::FreeRdp)
             }
         }
     }
 
-    fn open(&self, rdp_file: &Path, edit: bool) -> anyhow::Result<()> {
+    fn open(&self, rdp_file: &Path, edit: bool, password: Option<String>) -> anyhow::Result<()> {
         match &self {
             #[cfg(windows)]
             RdpBackend::Mstsc => {
+                if password.is_some() {
+                    log::warn!("mstsc doesn't support password_command, you will be prompted");
+                }
                 let mut cmd = Command::new("mstsc");
                 if edit {
                     cmd.arg("/edit");
                 }
                 cmd.arg(&rdp_file);
-                cmd.spawn()
-                    .context("Unable to launch Microsoft Remote Desktop")?;
+                cmd.spawn().map_err(|e| launch_error(e, "mstsc"))?;
+            }
+            RdpBackend::FreeRdp => {
+                if edit {
+                    bail!("FreeRDP doesn't support editing connections");
+                }
+                let (program, version) = find_freerdp(&FREERDP_PROGRAMS)?;
+                let mut cmd = Command::new(program);
+                let Some(password) = password else {
+                    cmd.arg(rdp_file);
+                    cmd.spawn().map_err(|e| launch_error(e, program))?;
+                    return Ok(());
+                };
+                // Pass the arguments on stdin so that the password doesn't appear in argv, which
+                // needs FreeRDP 3
+                if version < 3 {
+                    bail!(
+                        "password_command needs FreeRDP 3 or later (for /args-from), \
+                        but {program} is version {version}"
+                    );
+                }
+                let mut child = cmd
+                    .arg("/args-from:stdin")
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| launch_error(e, program))?;
+                let mut stdin = child.stdin.take().unwrap();
+                writeln!(stdin, "{}\n/p:{password}", rdp_file.display())
+                    .context("Unable to pass arguments to FreeRDP")?;
             }
-            #[allow(unreachable_patterns)]
-            _ => unimplemented!(),
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freerdp_versions() {
+        let v2 = "This is FreeRDP version 2.11.7 (2.11.7)\n";
+        assert_eq!(parse_freerdp_version(v2), Some(2));
+        let v3 = "This is FreeRDP version 3.5.1 (n/a)\nBuild configuration: ...\n";
+        assert_eq!(parse_freerdp_version(v3), Some(3));
+        assert_eq!(parse_freerdp_version("xfreerdp: unknown option"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prefers_freerdp3() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fake = |name: &str, version: &str| {
+            let path = dir.path().join(name);
+            let script = format!("#!/bin/sh\necho 'This is FreeRDP version {version} (n/a)'\n");
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path.display().to_string()
+        };
+        let v3 = fake("xfreerdp3", "3.5.1");
+        let v2 = fake("xfreerdp", "2.11.7");
+        let missing = dir.path().join("missing").display().to_string();
+
+        assert_eq!(find_freerdp(&[&v3, &v2]).unwrap(), (v3.as_str(), 3));
+        assert_eq!(find_freerdp(&[&missing, &v2]).unwrap(), (v2.as_str(), 2));
+        let error = find_freerdp(&[&missing]).unwrap_err();
+        assert_eq!(crate::exit::error_code(&error), 69);
+    }
+}
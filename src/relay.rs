@@ -0,0 +1,311 @@
+//! Relays a tunnel's local connections through remotec, so that it can count the traffic and
+//! close the tunnel once it's idle.
+//!
+//! remotec listens on the forwards' local ports itself, and ssh listens on internal ports that
+//! only the relay connects to.
+
+use crate::background::record_traffic;
+use crate::config::{Forward, ForwardListen, SshForwardArgument, TunnelProfile};
+use crate::tunnel::listen_address;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+/// How often the traffic counts are written to the tunnel's state
+const RECORD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Traffic through a forward
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Traffic {
+    pub connections: u64,
+    pub active: u64,
+    /// Bytes sent to the remote end
+    pub sent: u64,
+    /// Bytes received from the remote end
+    pub received: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    active: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+struct Shared {
+    /// Counters for each relayed forward
+    counters: Vec<Counters>,
+    last_activity: Mutex<Instant>,
+    expired: AtomicBool,
+}
+
+impl Shared {
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// How long the relay has been idle, where an open connection is never idle (even without
+    /// traffic, e.g. an idle database session)
+    fn idle(&self) -> Duration {
+        if self
+            .counters
+            .iter()
+            .any(|c| c.active.load(Ordering::Relaxed) > 0)
+        {
+            return Duration::ZERO;
+        }
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    fn traffic(&self) -> Vec<Traffic> {
+        self.counters
+            .iter()
+            .map(|c| Traffic {
+                connections: c.connections.load(Ordering::Relaxed),
+                active: c.active.load(Ordering::Relaxed),
+                sent: c.sent.load(Ordering::Relaxed),
+                received: c.received.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// A forward that is relayed: remotec listens on its local port and connects to ssh's port
+struct Relayed {
+    index: usize,
+    listener: TcpListener,
+    ssh_port: u16,
+}
+
+pub struct Relay {
+    profile: String,
+    relayed: Mutex<Vec<Relayed>>,
+    /// ssh's internal port for each forward, if it is relayed
+    ssh_ports: Vec<Option<u16>>,
+    idle_timeout: Option<Duration>,
+    shared: Arc<Shared>,
+    started: AtomicBool,
+}
+
+impl Relay {
+    /// Listens on the local TCP ports of the forwards, if the profile uses a relay or systemd
+    /// passed the listening sockets
+    pub fn for_profile(
+        profile: &TunnelProfile,
+        forwards: &[SshForwardArgument],
+        activated: Vec<TcpListener>,
+    ) -> anyhow::Result<Option<Self>> {
+        if !profile.relay && profile.idle_timeout.is_none() && activated.is_empty() {
+            return Ok(None);
+        }
+        let activated_count = activated.len();
+        let mut activated = activated.into_iter();
+        let mut relayed = Vec::new();
+        let mut ssh_ports = Vec::new();
+        for (index, f) in forwards.iter().enumerate() {
+            // Unix sockets aren't relayed
+            let Some(ForwardListen::Port { bind_address, port }) = f.forward.local_listen() else {
+                ssh_ports.push(None);
+                continue;
+            };
+            let listener = match activated.next() {
+                Some(listener) => listener,
+                None if activated_count > 0 => bail!(
+                    "systemd passed {activated_count} sockets, but the tunnel has more local ports"
+                ),
+                None => TcpListener::bind((listen_address(bind_address.as_deref()), port))
+                    .with_context(|| format!("Unable to listen on {}", f.description()))?,
+            };
+            let ssh_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .and_then(|l| l.local_addr())
+                .context("Unable to find a free local port")?
+                .port();
+            relayed.push(Relayed {
+                index,
+                listener,
+                ssh_port,
+            });
+            ssh_ports.push(Some(ssh_port));
+        }
+        Ok(Some(Relay {
+            profile: profile.name.clone(),
+            relayed: Mutex::new(relayed),
+            ssh_ports,
+            idle_timeout: profile.idle_timeout.map(Duration::from_secs),
+            shared: Arc::new(Shared {
+                counters: forwards.iter().map(|_| Counters::default()).collect(),
+                last_activity: Mutex::new(Instant::now()),
+                expired: AtomicBool::new(false),
+            }),
+            started: AtomicBool::new(false),
+        }))
+    }
+
+    /// The forwards for ssh, which listen on internal ports in place of the relayed ones
+    pub fn ssh_forwards(&self, forwards: &[SshForwardArgument]) -> Vec<SshForwardArgument> {
+        forwards
+            .iter()
+            .zip(&self.ssh_ports)
+            .map(|(f, ssh_port)| {
+                let mut f = f.clone();
+                if let Some(ssh_port) = *ssh_port {
+                    match &mut f.forward {
+                        Forward::Local { listen, .. } => {
+                            *listen = ForwardListen::Port {
+                                bind_address: None,
+                                port: ssh_port,
+                            }
+                        }
+                        Forward::Dynamic { bind_address, port } => {
+                            *bind_address = None;
+                            *port = ssh_port;
+                        }
+                        Forward::Remote { .. } => {}
+                    }
+                }
+                f
+            })
+            .collect()
+    }
+
+    /// Starts relaying connections (once ssh is listening), and watching for the idle timeout
+    pub fn start(&self) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.shared.touch();
+        for relayed in self.relayed.lock().unwrap().drain(..) {
+            let shared = self.shared.clone();
+            spawn(move || accept(relayed, shared));
+        }
+        let shared = self.shared.clone();
+        let profile = self.profile.clone();
+        let idle_timeout = self.idle_timeout;
+        spawn(move || watch(profile, idle_timeout, shared));
+    }
+
+    /// Whether the tunnel has been idle for longer than the timeout
+    pub fn expired(&self) -> bool {
+        self.shared.expired.load(Ordering::SeqCst)
+    }
+}
+
+fn accept(relayed: Relayed, shared: Arc<Shared>) {
+    for client in relayed.listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("Unable to accept connection: {e}");
+                continue;
+            }
+        };
+        let server = match TcpStream::connect((Ipv4Addr::LOCALHOST, relayed.ssh_port)) {
+            Ok(server) => server,
+            Err(e) => {
+                log::warn!("Unable to connect to ssh: {e}");
+                continue;
+            }
+        };
+        let counters = &shared.counters[relayed.index];
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        counters.active.fetch_add(1, Ordering::Relaxed);
+        shared.touch();
+        let shared = shared.clone();
+        let index = relayed.index;
+        spawn(move || {
+            if let Err(e) = relay_connection(client, server, index, &shared) {
+                log::debug!("Relayed connection closed: {e}");
+            }
+            shared.counters[index]
+                .active
+                .fetch_sub(1, Ordering::Relaxed);
+            // The idle time counts from when the last connection closes
+            shared.touch();
+        });
+    }
+}
+
+fn relay_connection(
+    client: TcpStream,
+    server: TcpStream,
+    index: usize,
+    shared: &Arc<Shared>,
+) -> anyhow::Result<()> {
+    let upload = {
+        let (client, server, shared) = (client.try_clone()?, server.try_clone()?, shared.clone());
+        spawn(move || copy(client, server, &shared, &shared.counters[index].sent))
+    };
+    let download = copy(server, client, shared, &shared.counters[index].received);
+    upload.join().unwrap()?;
+    download
+}
+
+/// Copies until the end of `from`, counting the bytes
+fn copy(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    shared: &Shared,
+    counter: &AtomicU64,
+) -> anyhow::Result<()> {
+    let mut buffer = [0; 16384];
+    loop {
+        let read = from.read(&mut buffer)?;
+        if read == 0 {
+            let _ = to.shutdown(Shutdown::Write);
+            return Ok(());
+        }
+        to.write_all(&buffer[..read])?;
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+        shared.touch();
+    }
+}
+
+/// Records the traffic periodically, and flags the relay as expired once it's idle for too long
+fn watch(profile: String, idle_timeout: Option<Duration>, shared: Arc<Shared>) {
+    let mut last_record = Instant::now();
+    loop {
+        sleep(Duration::from_secs(1));
+        if last_record.elapsed() >= RECORD_INTERVAL {
+            if let Err(e) = record_traffic(&profile, shared.traffic()) {
+                log::debug!("Unable to record traffic: {e:#}");
+            }
+            last_record = Instant::now();
+        }
+        let idle = shared.idle();
+        if let Some(timeout) = idle_timeout {
+            if idle >= timeout {
+                log::info!(
+                    "Closing tunnel `{profile}` after {}s without any connections",
+                    idle.as_secs()
+                );
+                shared.expired.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_connections_are_not_idle() {
+        let shared = Shared {
+            counters: vec![Counters::default(), Counters::default()],
+            last_activity: Mutex::new(Instant::now() - Duration::from_secs(600)),
+            expired: AtomicBool::new(false),
+        };
+        assert!(shared.idle() >= Duration::from_secs(600));
+        shared.counters[1].active.store(1, Ordering::Relaxed);
+        assert_eq!(shared.idle(), Duration::ZERO);
+        shared.counters[1].active.store(0, Ordering::Relaxed);
+        assert!(shared.idle() >= Duration::from_secs(600));
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory for sockets and state that only live as long as the user's session
+pub fn runtime_directory() -> anyhow::Result<PathBuf> {
+    let dir = dirs::runtime_dir()
+        .or_else(dirs::cache_dir)
+        .context("Unable to get runtime directory")?
+        .join("remotec");
+    if !dir.exists() {
+        fs::create_dir_all(&dir).context("Unable to create runtime directory")?;
+    }
+    Ok(dir)
+}
+
+/// A named directory within the runtime directory
+pub fn runtime_subdirectory(name: &str) -> anyhow::Result<PathBuf> {
+    let dir = runtime_directory()?.join(name);
+    if !dir.exists() {
+        fs::create_dir_all(&dir).with_context(|| format!("Unable to create {name} directory"))?;
+    }
+    Ok(dir)
+}
+
+/// Writes a file by renaming a temporary file over it, so that another instance of remotec never
+/// reads it half written
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+/// A short hash of some text (16 hex digits), for naming files after it
+pub fn short_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Converts a profile name into something safe to use as a file name
+pub fn file_name(profile: &str) -> String {
+    profile
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
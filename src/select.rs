@@ -1,4 +1,5 @@
-use crate::config::{CommandProfile, RdpProfile, SshProfile, TunnelProfile};
+use crate::config::{CommandProfile, RdpProfile, SshProfile, TunnelGroup, TunnelProfile};
+use crate::exit::Failure;
 use anyhow::Context;
 
 pub trait NamedProfile {
@@ -36,6 +37,16 @@ impl NamedProfile for TunnelProfile {
     }
 }
 
+impl NamedProfile for TunnelGroup {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+}
+
 impl NamedProfile for CommandProfile {
     fn name(&self) -> &str {
         &self.name
@@ -59,7 +70,8 @@ pub fn select_profile_by_name<'a, T: NamedProfile>(
     let profile = matched
         .into_iter()
         .next()
-        .context(format!("No {profile_type} profile found for `{name}`"))?;
+        .context(format!("No {profile_type} profile found for `{name}`"))
+        .context(Failure::ProfileNotFound)?;
     if print_description {
         if let Some(description) = profile.description() {
             log::info!("Description: {}", description);
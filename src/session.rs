@@ -0,0 +1,191 @@
+use crate::config::{Multiplexer, SshProfile};
+use crate::Ssh;
+use anyhow::bail;
+
+/// The remote session to attach to, if any
+pub fn session<'a>(profile: &'a SshProfile, cli: &'a Ssh) -> Option<(Multiplexer, &'a str)> {
+    if cli.no_session {
+        return None;
+    }
+    let multiplexer = multiplexer(profile);
+    if let Some(name) = &cli.session {
+        return Some((multiplexer, name));
+    }
+    profile
+        .session
+        .as_ref()
+        .map(|s| (multiplexer, s.name.as_str()))
+}
+
+/// The multiplexer configured for the profile, defaulting to tmux
+pub fn multiplexer(profile: &SshProfile) -> Multiplexer {
+    profile
+        .session
+        .as_ref()
+        .map(|s| s.multiplexer)
+        .unwrap_or_default()
+}
+
+impl Multiplexer {
+    /// Remote command that attaches to the named session, creating it if it doesn't exist.
+    ///
+    /// screen uses `-D -R`, which detaches the session from any other terminal before attaching,
+    /// because `-x -R` doesn't reliably create a session that doesn't exist.
+    pub fn attach_command(self, name: &str) -> Vec<String> {
+        let args: &[&str] = match self {
+            Multiplexer::Tmux => &["tmux", "new-session", "-A", "-s", name],
+            Multiplexer::Screen => &["screen", "-D", "-R", "-S", name],
+        };
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Remote command that lists the existing sessions
+    pub fn list_command(self) -> Vec<String> {
+        let args: &[&str] = match self {
+            Multiplexer::Tmux => &["tmux", "list-sessions"],
+            Multiplexer::Screen => &["screen", "-list"],
+        };
+        args.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+/// Quotes a command so that it survives being joined and parsed by the remote shell
+pub fn remote_command(command: Vec<String>) -> Vec<String> {
+    command
+        .iter()
+        .map(|s| shell_words::quote(s).into_owned())
+        .collect()
+}
+
+/// Wraps the session's remote command (or the user's login shell) in a script that first
+/// changes to the profile's directory, exports its environment and runs its startup command
+pub fn startup_command(
+    profile: &SshProfile,
+    command: Option<Vec<String>>,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let export = profile.set_env != Some(true) && !profile.env.is_empty();
+    if profile.remote_dir.is_none() && profile.startup_command.is_none() && !export {
+        return Ok(command);
+    }
+    let mut steps = Vec::new();
+    if let Some(dir) = &profile.remote_dir {
+        let dir = match dir.strip_prefix("~/") {
+            Some(relative) => format!("\"$HOME\"/{}", shell_words::quote(relative)),
+            None => shell_words::quote(dir).into_owned(),
+        };
+        steps.push(format!("cd {dir}"));
+    }
+    if export {
+        check_variable_names(profile)?;
+        for (key, value) in &profile.env {
+            steps.push(format!("export {key}={}", shell_words::quote(value)));
+        }
+    }
+    if let Some(startup) = &profile.startup_command {
+        steps.push(startup.clone());
+    }
+    let exec = match command {
+        Some(command) => format!("exec {}", shell_words::join(command)),
+        None => "exec \"$SHELL\" -l".to_string(),
+    };
+    // A failed step (e.g. a missing directory) ends the session rather than starting a shell
+    let script = format!("{} && {exec}", steps.join(" && "));
+    Ok(Some(vec!["sh".to_string(), "-c".to_string(), script]))
+}
+
+/// Options that send the profile's environment using ssh's `SetEnv`
+pub fn set_env_args(profile: &SshProfile) -> anyhow::Result<Vec<String>> {
+    if profile.set_env == Some(false) {
+        return Ok(Vec::new());
+    }
+    check_variable_names(profile)?;
+    Ok(profile
+        .env
+        .iter()
+        .flat_map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            ["-o".to_string(), format!("SetEnv={key}=\"{value}\"")]
+        })
+        .collect())
+}
+
+fn check_variable_names(profile: &SshProfile) -> anyhow::Result<()> {
+    for key in profile.env.keys() {
+        if !is_variable_name(key) {
+            bail!("`{key}` isn't a valid environment variable name");
+        }
+    }
+    Ok(())
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::ssh_profile as profile;
+    use serde_json::json;
+
+    fn script(profile: &SshProfile, command: Option<Vec<String>>) -> String {
+        startup_command(profile, command).unwrap().unwrap()[2].clone()
+    }
+
+    #[test]
+    fn no_startup_steps() {
+        let command = Some(vec!["tmux".to_string()]);
+        assert_eq!(
+            startup_command(&profile(json!({})), command.clone()).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn failed_step_ends_session() {
+        let profile = profile(json!({"remote_dir": "~/my src", "startup_command": "make"}));
+        assert_eq!(
+            script(&profile, None),
+            "cd \"$HOME\"/'my src' && make && exec \"$SHELL\" -l"
+        );
+        let command = Multiplexer::Screen.attach_command("main");
+        assert_eq!(
+            script(&profile, Some(command)),
+            "cd \"$HOME\"/'my src' && make && exec screen -D -R -S main"
+        );
+    }
+
+    #[test]
+    fn env_falls_back_to_export() {
+        let env = json!({"A": "it's", "B": "\"x\""});
+        let both = profile(json!({ "env": env }));
+        assert_eq!(
+            script(&both, None),
+            "export A='it'\\''s' && export B='\"x\"' && exec \"$SHELL\" -l"
+        );
+        assert_eq!(
+            set_env_args(&both).unwrap(),
+            ["-o", "SetEnv=A=\"it's\"", "-o", "SetEnv=B=\"\\\"x\\\"\""]
+        );
+
+        let set_env = profile(json!({"env": env, "set_env": true}));
+        assert_eq!(startup_command(&set_env, None).unwrap(), None);
+        assert_eq!(set_env_args(&set_env).unwrap().len(), 4);
+
+        let export = profile(json!({"env": env, "set_env": false}));
+        assert!(startup_command(&export, None).unwrap().is_some());
+        assert!(set_env_args(&export).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_variable_names() {
+        let env = json!({"A B": "1"});
+        let both = profile(json!({ "env": env }));
+        assert!(startup_command(&both, None).is_err());
+        assert!(set_env_args(&both).is_err());
+        assert!(set_env_args(&profile(json!({"env": env, "set_env": true}))).is_err());
+        assert!(startup_command(&profile(json!({"env": env, "set_env": false})), None).is_err());
+    }
+}
@@ -1,62 +1,277 @@
-use crate::config::{SshJumpHost, SshProfile};
+use crate::askpass::askpass_env;
+use crate::config::{SshBackend, SshJumpHost, SshProfile};
+use crate::exit::{launch_error, status_code};
+use crate::hostkeys::{pinning_args, KnownHost};
+use crate::mux::multiplex_args;
+use crate::preflight::check_banner;
 use crate::select::select_profile_by_name;
+use crate::session::{multiplexer, remote_command, session, set_env_args, startup_command};
 use crate::{Config, Ssh, SshCommon};
-use anyhow::{bail, Context};
-use std::process::Command;
+use anyhow::bail;
+use std::process::{Child, Command};
 
-pub fn ssh_args(
-    config: &Config,
+/// A resolved SSH connection for a profile
+#[derive(Clone)]
+pub struct SshTarget<'a> {
+    pub profile: &'a SshProfile,
+    /// Options to pass to ssh, excluding the destination
+    pub options: Vec<String>,
+    /// The `user@host` destination
+    pub destination: String,
+    /// The jump hosts followed by the destination
+    pub hops: Vec<Hop<'a>>,
+    /// Environment variables to set for ssh
+    pub env: Vec<(String, String)>,
+    /// Whether to allocate a terminal on the remote host
+    pub tty: bool,
+    pub backend: SshBackend,
+    /// Whether to check the first host's SSH banner before connecting
+    pub preflight: bool,
+}
+
+impl SshTarget<'_> {
+    /// Checks that the first host is reachable and running SSH, if enabled
+    pub fn preflight(&self) -> anyhow::Result<()> {
+        if !self.preflight {
+            return Ok(());
+        }
+        let first = &self.hops[0];
+        let banner = check_banner(first.hostname, first.port())?;
+        log::info!("{} is running {}", first.hostname, banner);
+        Ok(())
+    }
+}
+
+/// A host in the connection chain
+#[derive(Clone)]
+pub struct Hop<'a> {
+    pub username: String,
+    pub hostname: &'a str,
+    pub port: Option<u16>,
+    pub host_keys: &'a [String],
+}
+
+impl Hop<'_> {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(22)
+    }
+}
+
+pub fn ssh_target<'a>(
+    config: &'a Config,
     cli: &SshCommon,
     profile: &str,
     print_description: bool,
-) -> anyhow::Result<Vec<String>> {
+) -> anyhow::Result<SshTarget<'a>> {
     let profile = select_profile_by_name("SSH", &config.ssh, profile, print_description)?;
     let jumps = jump_hosts(profile, cli)?;
     let address = profile.address.choose_address(cli.ipv4, cli.ipv6)?;
     let username = username(profile, config);
 
-    let mut args = Vec::new();
-    if jumps.is_empty() {
-        args.push(format!("{username}@{address}"));
-        if let Some(port) = profile.address.port {
-            args.push("-p".to_string());
-            args.push(port.to_string());
+    let mut hops = jumps
+        .iter()
+        .map(|j| Hop {
+            username: j.username.clone().unwrap_or_else(|| username.clone()),
+            hostname: &j.hostname,
+            port: j.port,
+            host_keys: &j.host_keys,
+        })
+        .collect::<Vec<_>>();
+    hops.push(Hop {
+        username,
+        hostname: address,
+        port: profile.address.port,
+        host_keys: &profile.host_keys,
+    });
+    let destination = hops.last().unwrap();
+
+    let backend = config.ssh_defaults.backend.unwrap_or(SshBackend::OpenSsh);
+    let mut options = match backend {
+        SshBackend::OpenSsh => {
+            let mut options = multiplex_args(profile)?;
+            let hosts = hops
+                .iter()
+                .map(|h| KnownHost::new(h.hostname, h.port, h.host_keys))
+                .collect::<Vec<_>>();
+            options.append(&mut pinning_args(profile, &hosts)?);
+            options
         }
-    } else {
-        args.push("-J".to_string());
-        for j in jumps {
-            let username = j.username.as_ref().unwrap_or(&username);
-            let port = j.port.map(|p| format!(":{p}")).unwrap_or_else(String::new);
-            args.push(format!("{username}@{}{port}", j.hostname));
+        // The native client checks the pinned host keys itself, and has no options
+        SshBackend::Native => {
+            if profile.multiplex {
+                bail!("The native SSH backend doesn't support multiplex");
+            }
+            Vec::new()
         }
-        let port = profile
-            .address
-            .port
-            .map(|p| format!(":{p}"))
-            .unwrap_or_else(String::new);
-        args.push(format!("{username}@{}{port}", address));
+    };
+    if hops.len() > 1 {
+        options.push("-J".to_string());
+        let jumps = hops[..hops.len() - 1]
+            .iter()
+            .map(|j| {
+                let port = j.port.map(|p| format!(":{p}")).unwrap_or_default();
+                format!("{}@{}{port}", j.username, j.hostname)
+            })
+            .collect::<Vec<_>>();
+        options.push(jumps.join(","));
+    }
+    if let Some(port) = destination.port {
+        options.push("-p".to_string());
+        options.push(port.to_string());
     }
-    Ok(args)
+    let destination = format!("{}@{}", destination.username, destination.hostname);
+    let env = match &profile.password_command {
+        Some(command) => askpass_env(command)?,
+        None => Vec::new(),
+    };
+    Ok(SshTarget {
+        profile,
+        options,
+        destination,
+        hops,
+        env,
+        tty: false,
+        backend,
+        preflight: cli.preflight || profile.preflight,
+    })
 }
 
-pub fn invoke_ssh(args: Vec<String>, stdout: bool) -> anyhow::Result<()> {
-    let command = format!("ssh {}", args.join(" "));
+/// Runs a program and returns its exit code (or 0 if the command was only printed)
+pub fn invoke(
+    program: &str,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    stdout: bool,
+) -> anyhow::Result<i32> {
+    let command =
+        shell_words::join(std::iter::once(program).chain(args.iter().map(String::as_str)));
     if stdout {
         println!("{}", command);
-    } else {
-        log::info!("Invoking: `{}`", command);
-        Command::new("ssh")
-            .args(args)
-            .status()
-            .context("Error invoking ssh")?;
+        return Ok(0);
+    }
+    log::info!("Invoking: `{}`", command);
+    let status = Command::new(program)
+        .args(args)
+        .envs(env)
+        .status()
+        .map_err(|e| launch_error(e, program))?;
+    Ok(status_code(status))
+}
+
+/// Runs ssh for the target, with an optional remote command
+pub fn invoke_ssh(target: SshTarget, command: Vec<String>, stdout: bool) -> anyhow::Result<i32> {
+    if !stdout {
+        target.preflight()?;
+    }
+    if let SshBackend::Native = target.backend {
+        if stdout {
+            bail!("The native SSH backend can't print a command");
+        }
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "native-ssh")] {
+                return crate::native::run(target, command);
+            } else {
+                return Err(native_unavailable());
+            }
+        }
+    }
+    let env = target.env.clone();
+    invoke("ssh", ssh_args(target, command), env, stdout)
+}
+
+/// Starts ssh (which must be the configured backend) without waiting for it to exit
+pub fn spawn_ssh(target: SshTarget, command: Vec<String>) -> anyhow::Result<Child> {
+    ssh_command(target, command)
+        .spawn()
+        .map_err(|e| launch_error(e, "ssh"))
+}
+
+/// The ssh command for the target, with an optional remote command, to be spawned by the caller
+pub fn ssh_command(target: SshTarget, command: Vec<String>) -> Command {
+    let env = target.env.clone();
+    let args = ssh_args(target, command);
+    log::info!(
+        "Invoking: `{}`",
+        shell_words::join(std::iter::once("ssh").chain(args.iter().map(String::as_str)))
+    );
+    let mut ssh = Command::new("ssh");
+    ssh.args(args).envs(env);
+    ssh
+}
+
+fn ssh_args(target: SshTarget, command: Vec<String>) -> Vec<String> {
+    let mut args = target.options;
+    if target.tty {
+        args.push("-t".to_string());
     }
-    Ok(())
+    args.push(target.destination);
+    args.extend(command);
+    args
 }
 
-pub fn launch_ssh(config: &Config, cli: &Ssh) -> anyhow::Result<()> {
-    let args = ssh_args(config, &cli.common, &cli.name, true)?;
-    invoke_ssh(args, cli.common.stdout)?;
-    Ok(())
+pub fn launch_ssh(config: &Config, cli: &Ssh) -> anyhow::Result<i32> {
+    let mut target = ssh_target(config, &cli.common, &cli.name, true)?;
+    if cli.list_sessions {
+        let command = multiplexer(target.profile).list_command();
+        return invoke_ssh(target, remote_command(command), cli.common.stdout);
+    }
+    let command = session(target.profile, cli).map(|(m, name)| m.attach_command(name));
+    let command = startup_command(target.profile, command)?;
+    match target.backend {
+        SshBackend::OpenSsh => target.options.append(&mut set_env_args(target.profile)?),
+        // The variables can only be exported by the wrapper script
+        SshBackend::Native if target.profile.set_env == Some(true) => {
+            bail!("The native SSH backend doesn't support set_env")
+        }
+        SshBackend::Native => {}
+    }
+    if cli.mosh || target.profile.mosh {
+        if !cli.common.stdout {
+            target.preflight()?;
+        }
+        let env = target.env.clone();
+        let args = mosh_args(target, command);
+        return invoke("mosh", args, env, cli.common.stdout);
+    }
+    if let Some(command) = command {
+        target.tty = true;
+        return invoke_ssh(target, remote_command(command), cli.common.stdout);
+    }
+    invoke_ssh(target, Vec::new(), cli.common.stdout)
+}
+
+/// Arguments for mosh, which uses ssh (with the same options) to start the server
+fn mosh_args(target: SshTarget, command: Option<Vec<String>>) -> Vec<String> {
+    if target.hops.len() > 1 {
+        // ssh gets to the server through the jump hosts, but the session itself doesn't
+        log::warn!(
+            "Mosh only uses the jump hosts to start the server, \
+            its UDP ports must be reachable directly"
+        );
+    }
+    let ssh =
+        shell_words::join(std::iter::once("ssh").chain(target.options.iter().map(String::as_str)));
+    let mut args = vec![format!("--ssh={ssh}")];
+    if let Some(server) = &target.profile.mosh_server {
+        args.push(format!("--server={server}"));
+    }
+    if let Some(ports) = &target.profile.mosh_ports {
+        args.push(format!("--port={ports}"));
+    }
+    args.push(target.destination);
+    if let Some(mut command) = command {
+        // mosh-server runs the command directly rather than through a shell
+        args.push("--".to_string());
+        args.append(&mut command);
+    }
+    args
+}
+
+/// The error for when the native backend is configured but wasn't compiled in
+#[cfg(not(feature = "native-ssh"))]
+pub fn native_unavailable() -> anyhow::Error {
+    anyhow::anyhow!("remotec was built without the `native-ssh` feature")
+        .context(crate::exit::Failure::BackendMissing)
 }
 
 fn jump_hosts<'a>(
@@ -72,15 +287,71 @@ fn jump_hosts<'a>(
     if cli.disable_jump_hosts || profile.disable_jump_hosts {
         return Ok(Vec::new());
     }
-    return Ok(profile.jump_hosts.iter().collect());
+    Ok(profile.jump_hosts.iter().collect())
 }
 
 fn username(profile: &SshProfile, config: &Config) -> String {
     if let Some(username) = &profile.username {
         return username.to_string();
     }
-    if let Some(username) = &config.rdp_defaults.username {
+    if let Some(username) = &config.ssh_defaults.username {
         return username.to_string();
     }
     whoami::username()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures;
+
+    fn native_config(profile: serde_json::Value) -> Config {
+        fixtures::config(serde_json::json!({
+            "ssh": [fixtures::ssh_json(profile)],
+            "ssh_defaults": {"backend": "Native"}
+        }))
+    }
+
+    #[test]
+    fn native_checks_pins_itself() {
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+        let config = native_config(serde_json::json!({"host_keys": [key]}));
+        let target = ssh_target(&config, &SshCommon::default(), "test", false).unwrap();
+        assert!(target.options.is_empty());
+        assert_eq!(target.hops[0].host_keys, [key]);
+    }
+
+    #[test]
+    fn mosh_starts_the_server_over_ssh() {
+        let config = fixtures::config(serde_json::json!({"ssh": [fixtures::ssh_json(
+            serde_json::json!({
+                "port": 2222,
+                "username": "me",
+                "mosh_server": "~/bin/mosh-server",
+                "mosh_ports": "60000:60010",
+                "jump_hosts": [{"hostname": "bastion"}]
+            })
+        )]}));
+        let target = ssh_target(&config, &SshCommon::default(), "test", false).unwrap();
+        let command = vec!["tmux".to_string(), "new".to_string()];
+        assert_eq!(
+            mosh_args(target, Some(command)),
+            [
+                "--ssh=ssh -J me@bastion -p 2222",
+                "--server=~/bin/mosh-server",
+                "--port=60000:60010",
+                "me@example.com",
+                "--",
+                "tmux",
+                "new",
+            ]
+        );
+    }
+
+    #[test]
+    fn native_rejects_multiplex() {
+        let config = native_config(serde_json::json!({"multiplex": true}));
+        assert!(ssh_target(&config, &SshCommon::default(), "test", false).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! systemd user units that keep a tunnel open while the user is logged in
+
+use crate::config::{ForwardListen, TunnelProfile};
+use crate::runtime::file_name;
+use crate::tunnel::{host_port, listen_address};
+use crate::SshCommon;
+use anyhow::{bail, Context};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Seconds that systemd waits before restarting a tunnel that failed
+const RESTART_SEC: u32 = 10;
+
+fn unit_directory() -> anyhow::Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("Unable to get config directory")?
+        .join("systemd")
+        .join("user"))
+}
+
+fn unit_name(profile: &TunnelProfile, extension: &str) -> String {
+    format!("remotec-tunnel-{}.{extension}", file_name(&profile.name))
+}
+
+/// Writes the units for a tunnel: a service, and a socket that starts it if `socket` is set
+pub fn install_systemd(
+    profile: &TunnelProfile,
+    common: &SshCommon,
+    socket: bool,
+) -> anyhow::Result<i32> {
+    let exe = std::env::current_exe().context("Unable to get path of remotec")?;
+    let agent = std::env::var_os("SSH_AUTH_SOCK").map(PathBuf::from);
+    let agent = agent.as_deref().and_then(|agent| {
+        let relative = dirs::runtime_dir().and_then(|dir| runtime_relative(agent, &dir));
+        if relative.is_none() {
+            log::warn!(
+                "The tunnel can only use your SSH agent ({}) if systemd knows about it, run \
+                `systemctl --user import-environment SSH_AUTH_SOCK` after logging in",
+                agent.display()
+            );
+        }
+        relative
+    });
+    let mut units = vec![(
+        unit_name(profile, "service"),
+        render_service(profile, common, &exe.display().to_string(), socket, agent),
+    )];
+    if socket {
+        units.push((unit_name(profile, "socket"), render_socket(profile)?));
+    }
+    let dir = unit_directory()?;
+    fs::create_dir_all(&dir).context("Unable to create systemd user unit directory")?;
+    for (name, contents) in &units {
+        let path = dir.join(name);
+        fs::write(&path, contents)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        log::info!("Wrote {}", path.display());
+    }
+    log::info!(
+        "Start it with `systemctl --user daemon-reload && systemctl --user enable --now {}`",
+        units.last().unwrap().0
+    );
+    Ok(0)
+}
+
+/// Stops and removes a tunnel's units
+pub fn uninstall_systemd(profile: &TunnelProfile) -> anyhow::Result<i32> {
+    let dir = unit_directory()?;
+    let mut removed = false;
+    for name in [unit_name(profile, "socket"), unit_name(profile, "service")] {
+        let path = dir.join(&name);
+        if !path.exists() {
+            continue;
+        }
+        systemctl(&["disable", "--now", &name]);
+        fs::remove_file(&path).with_context(|| format!("Unable to remove {}", path.display()))?;
+        log::info!("Removed {}", path.display());
+        removed = true;
+    }
+    if !removed {
+        log::warn!(
+            "No systemd units are installed for tunnel `{}`",
+            profile.name
+        );
+        return Ok(1);
+    }
+    systemctl(&["daemon-reload"]);
+    Ok(0)
+}
+
+/// Runs `systemctl --user`, only warning if it fails (e.g. on a system without systemd)
+fn systemctl(args: &[&str]) {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("`systemctl --user {}` failed ({status})", args.join(" ")),
+        Err(e) => log::warn!("Unable to run systemctl: {e}"),
+    }
+}
+
+/// The path of an SSH agent's socket within the runtime directory (`%t` in a unit), where it stays
+/// the same between logins, unlike the sockets of agents started by a login shell
+fn runtime_relative(agent: &Path, runtime_dir: &Path) -> Option<String> {
+    let relative = agent.strip_prefix(runtime_dir).ok()?.to_str()?;
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-".contains(c);
+    (!relative.is_empty() && relative.chars().all(plain)).then(|| relative.to_string())
+}
+
+fn render_service(
+    profile: &TunnelProfile,
+    common: &SshCommon,
+    exe: &str,
+    socket: bool,
+    agent: Option<String>,
+) -> String {
+    let mut command = vec![exe, "tunnel", &profile.name];
+    for (enabled, flag) in [
+        (common.ipv4, "--ipv4"),
+        (common.ipv6, "--ipv6"),
+        (common.use_jump_hosts, "--use-jump-hosts"),
+        (common.disable_jump_hosts, "--disable-jump-hosts"),
+        (common.preflight, "--preflight"),
+    ] {
+        if enabled {
+            command.push(flag);
+        }
+    }
+    let command = command.into_iter().map(quote).collect::<Vec<_>>();
+    let environment = agent
+        .map(|agent| format!("Environment=SSH_AUTH_SOCK=%t/{agent}\n"))
+        .unwrap_or_default();
+    let mut unit = format!(
+        "[Unit]\n\
+        Description={}\n\
+        \n\
+        [Service]\n\
+        {environment}\
+        ExecStart={}\n\
+        Restart=on-failure\n\
+        RestartSec={RESTART_SEC}\n",
+        description(profile),
+        command.join(" ")
+    );
+    // A socket activated service is started by its socket instead
+    if !socket {
+        unit.push_str("\n[Install]\nWantedBy=default.target\n");
+    }
+    unit
+}
+
+fn render_socket(profile: &TunnelProfile) -> anyhow::Result<String> {
+    let mut listens = Vec::new();
+    for f in &profile.forwards {
+        match f.forward.local_listen() {
+            Some(ForwardListen::Port { port: 0, .. }) => bail!(
+                "Socket activation needs fixed local ports, but {} is `auto`",
+                f.description()
+            ),
+            Some(ForwardListen::Port { bind_address, port }) => listens.push(format!(
+                "ListenStream={}\n",
+                host_port(listen_address(bind_address.as_deref()), port)
+            )),
+            Some(ForwardListen::Socket(_)) => bail!(
+                "Socket activation only supports TCP ports, not {}",
+                f.description()
+            ),
+            None => {}
+        }
+    }
+    if listens.is_empty() {
+        bail!("Socket activation needs at least one forward with a local port");
+    }
+    Ok(format!(
+        "[Unit]\n\
+        Description={}\n\
+        \n\
+        [Socket]\n\
+        {}\
+        \n\
+        [Install]\n\
+        WantedBy=sockets.target\n",
+        description(profile),
+        listens.concat()
+    ))
+}
+
+fn description(profile: &TunnelProfile) -> String {
+    let description = match &profile.description {
+        Some(description) => format!("remotec tunnel {} ({description})", profile.name),
+        None => format!("remotec tunnel {}", profile.name),
+    };
+    description.replace('%', "%%").replace('\n', " ")
+}
+
+/// Quotes an argument for a unit's command line
+fn quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        /// The listening sockets passed by systemd socket activation (`LISTEN_FDS`), in the order
+        /// of the socket unit's `ListenStream` lines
+        pub fn activated_listeners() -> Vec<TcpListener> {
+            use std::os::unix::io::FromRawFd;
+            const LISTEN_FDS_START: i32 = 3;
+            let for_us = std::env::var("LISTEN_PID").ok() == Some(std::process::id().to_string());
+            let count = std::env::var("LISTEN_FDS")
+                .ok()
+                .and_then(|n| n.parse::<i32>().ok())
+                .filter(|_| for_us)
+                .unwrap_or(0);
+            // Don't pass them on to ssh
+            std::env::remove_var("LISTEN_PID");
+            std::env::remove_var("LISTEN_FDS");
+            std::env::remove_var("LISTEN_FDNAMES");
+            (LISTEN_FDS_START..LISTEN_FDS_START + count)
+                .map(|fd| {
+                    // Safe because systemd passes these descriptors to this process alone
+                    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                    // The duplicate is close-on-exec, so isn't inherited by ssh
+                    listener.try_clone().unwrap_or(listener)
+                })
+                .collect()
+        }
+    } else {
+        pub fn activated_listeners() -> Vec<TcpListener> {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(json: serde_json::Value) -> TunnelProfile {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn plain_service() {
+        let profile = tunnel(serde_json::json!({
+            "name": "db",
+            "description": "Postgres",
+            "ssh_profile": "bastion",
+            "forwards": [{"local_port": 5432, "remote_host": "db.internal", "remote_port": 5432}]
+        }));
+        let common = SshCommon {
+            disable_jump_hosts: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_service(&profile, &common, "/usr/bin/remotec", false, None),
+            "[Unit]\n\
+            Description=remotec tunnel db (Postgres)\n\
+            \n\
+            [Service]\n\
+            ExecStart=/usr/bin/remotec tunnel db --disable-jump-hosts\n\
+            Restart=on-failure\n\
+            RestartSec=10\n\
+            \n\
+            [Install]\n\
+            WantedBy=default.target\n"
+        );
+    }
+
+    #[test]
+    fn socket_activated_service() {
+        let profile = tunnel(serde_json::json!({
+            "name": "web",
+            "ssh_profile": "bastion",
+            "idle_timeout": 600,
+            "forwards": [
+                {"local_port": 8080, "remote_host": "app", "remote_port": 80},
+                {"local_port": 8443, "bind_address": "::", "remote_host": "app", "remote_port": 443},
+                {"type": "remote", "remote_port": 9000, "local_port": 3000}
+            ]
+        }));
+        assert_eq!(
+            render_service(
+                &profile,
+                &SshCommon::default(),
+                "/usr/bin/remotec",
+                true,
+                Some("ssh-agent.socket".to_string())
+            ),
+            "[Unit]\n\
+            Description=remotec tunnel web\n\
+            \n\
+            [Service]\n\
+            Environment=SSH_AUTH_SOCK=%t/ssh-agent.socket\n\
+            ExecStart=/usr/bin/remotec tunnel web\n\
+            Restart=on-failure\n\
+            RestartSec=10\n"
+        );
+        assert_eq!(
+            render_socket(&profile).unwrap(),
+            "[Unit]\n\
+            Description=remotec tunnel web\n\
+            \n\
+            [Socket]\n\
+            ListenStream=127.0.0.1:8080\n\
+            ListenStream=[::]:8443\n\
+            \n\
+            [Install]\n\
+            WantedBy=sockets.target\n"
+        );
+    }
+
+    #[test]
+    fn socket_needs_fixed_ports() {
+        let profile = tunnel(serde_json::json!({
+            "name": "web",
+            "ssh_profile": "bastion",
+            "forwards": [{"local_port": "auto", "remote_host": "app", "remote_port": 80}]
+        }));
+        assert!(render_socket(&profile).is_err());
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("/opt/my tools/remotec"), "\"/opt/my tools/remotec\"");
+        assert_eq!(quote("100%"), "100%%");
+        assert_eq!(quote("$HOME"), "$$HOME");
+        assert_eq!(quote(r#"say "hi"; \o/"#), r#""say \"hi\"; \\o/""#);
+
+        let profile = tunnel(serde_json::json!({
+            "name": "my db",
+            "ssh_profile": "bastion",
+            "description": "50% of\nthe time",
+            "forwards": []
+        }));
+        let service = render_service(
+            &profile,
+            &SshCommon::default(),
+            "/opt/my tools/remotec",
+            false,
+            None,
+        );
+        assert!(service.contains("Description=remotec tunnel my db (50%% of the time)\n"));
+        assert!(service.contains("ExecStart=\"/opt/my tools/remotec\" tunnel \"my db\"\n"));
+    }
+
+    #[test]
+    fn agent_sockets() {
+        let runtime = Path::new("/run/user/1000");
+        let relative = |agent: &str| runtime_relative(Path::new(agent), runtime);
+        assert_eq!(
+            relative("/run/user/1000/gcr/ssh").as_deref(),
+            Some("gcr/ssh")
+        );
+        // Started by a login shell, so it's somewhere else after the next login
+        assert_eq!(relative("/tmp/ssh-XXXXabcd/agent.1234"), None);
+        assert_eq!(relative("/run/user/1000/my agent"), None);
+    }
+}
@@ -1,54 +1,1055 @@
-use crate::config::SshForwardArgument;
+use crate::background::{
+    format_duration, launch_tunnel_action, mark_ready, register, running_state, start_background,
+    unregister,
+};
+use crate::config::{
+    Forward, ForwardListen, ForwardTarget, ReconnectPolicy, SshBackend, SshForwardArgument,
+    TunnelProfile, TunnelTransport,
+};
+use crate::exit::status_code;
+use crate::group::launch_group;
+use crate::kubectl::{kubectl_args, print_kubectl, spawn_kubectl};
+use crate::pac::serve_pac;
+use crate::relay::Relay;
+use crate::runtime::file_name;
 use crate::select::select_profile_by_name;
-use crate::ssh::{invoke_ssh, ssh_args};
-use crate::{Config, Tunnel};
-use anyhow::bail;
+use crate::ssh::{invoke, invoke_ssh, spawn_ssh, ssh_target, SshTarget};
+use crate::systemd::{activated_listeners, install_systemd, uninstall_systemd};
+use crate::{Config, Tunnel, TunnelAction};
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::Child;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait for the forwards to come up (including any password prompts)
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_SERVER_ALIVE_INTERVAL: u32 = 15;
+const DEFAULT_SERVER_ALIVE_COUNT_MAX: u32 = 3;
+const DEFAULT_INITIAL_DELAY: f64 = 1.0;
+const DEFAULT_MAX_DELAY: f64 = 60.0;
+const DEFAULT_JITTER: f64 = 0.2;
 
 impl SshForwardArgument {
+    pub fn description(&self) -> String {
+        match &self.name {
+            Some(name) => format!("`{name}` {}", self.forward.description()),
+            None => self.forward.description(),
+        }
+    }
+}
+
+impl Forward {
+    /// The ssh option for the forward, and its value
+    fn ssh_args(&self) -> [String; 2] {
+        match self {
+            Forward::Local { listen, target } => [
+                "-L".to_string(),
+                format!("{}:{}", listen.ssh_arg(), target.ssh_arg()),
+            ],
+            Forward::Remote { listen, target } => [
+                "-R".to_string(),
+                format!("{}:{}", listen.ssh_arg(), target.ssh_arg()),
+            ],
+            Forward::Dynamic { .. } => ["-D".to_string(), self.local_listen().unwrap().ssh_arg()],
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            Forward::Local { listen, target } => {
+                format!("{} -> {}", listen.description(), target.ssh_arg())
+            }
+            Forward::Remote { listen, target } => {
+                format!("remote {} -> {}", listen.description(), target.ssh_arg())
+            }
+            Forward::Dynamic { .. } => {
+                format!("SOCKS {}", self.local_listen().unwrap().description())
+            }
+        }
+    }
+
+    /// Where the forward listens on the local machine (if it does)
+    pub fn local_listen(&self) -> Option<ForwardListen> {
+        match self {
+            Forward::Local { listen, .. } => Some(listen.clone()),
+            Forward::Remote { .. } => None,
+            Forward::Dynamic { bind_address, port } => Some(ForwardListen::Port {
+                bind_address: bind_address.clone(),
+                port: *port,
+            }),
+        }
+    }
+
+    /// The local TCP port that the forward listens on (if it does)
+    pub fn local_port(&self) -> Option<u16> {
+        match self.local_listen()? {
+            ForwardListen::Port { port, .. } => Some(port),
+            ForwardListen::Socket(_) => None,
+        }
+    }
+
+    fn set_local_port(&mut self, value: u16) {
+        match self {
+            Forward::Local {
+                listen: ForwardListen::Port { port, .. },
+                ..
+            }
+            | Forward::Dynamic { port, .. } => *port = value,
+            _ => {}
+        }
+    }
+
+    /// Warns if the forward listens on an address that other machines can connect to
+    fn warn_if_exposed(&self) {
+        let (bind_address, side) = match self {
+            Forward::Local {
+                listen: ForwardListen::Port { bind_address, .. },
+                ..
+            }
+            | Forward::Dynamic { bind_address, .. } => (bind_address, "this machine"),
+            Forward::Remote {
+                listen: ForwardListen::Port { bind_address, .. },
+                ..
+            } => (bind_address, "the remote host"),
+            _ => return,
+        };
+        if let Some(address) = bind_address {
+            if !is_loopback(address) {
+                log::warn!(
+                    "Forward {} listens on `{address}` of {side}, so other machines can \
+                    connect to it",
+                    self.description()
+                );
+            }
+        }
+    }
+}
+
+impl ForwardListen {
     fn ssh_arg(&self) -> String {
-        format!(
-            "{}:{}:{}",
-            self.local_port, self.remote_host, self.remote_port
-        )
+        match self {
+            ForwardListen::Port {
+                bind_address: Some(address),
+                port,
+            } => host_port(address, *port),
+            ForwardListen::Port { port, .. } => port.to_string(),
+            ForwardListen::Socket(path) => path.clone(),
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            ForwardListen::Port { bind_address, port } => {
+                host_port(bind_address.as_deref().unwrap_or("localhost"), *port)
+            }
+            ForwardListen::Socket(path) => path.clone(),
+        }
+    }
+}
+
+impl ForwardTarget {
+    fn ssh_arg(&self) -> String {
+        match self {
+            ForwardTarget::Host { host, port } => host_port(host, *port),
+            ForwardTarget::Socket(path) => path.clone(),
+        }
+    }
+}
+
+/// Formats a `host:port` pair, with brackets around IPv6 addresses
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+fn is_loopback(address: &str) -> bool {
+    address == "localhost"
+        || address
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
+}
+
+/// The local address to listen on for a forward's bind address, following ssh's rules
+pub fn listen_address(bind_address: Option<&str>) -> &str {
+    match bind_address {
+        None | Some("localhost") => "127.0.0.1",
+        Some("") | Some("*") => "0.0.0.0",
+        Some(address) => address,
     }
 }
 
-pub fn launch_tunnel(config: &Config, cli: &Tunnel) -> anyhow::Result<()> {
-    let profile = select_profile_by_name("Tunnel", &config.tunnels, &cli.name, true)?;
+pub fn launch_tunnel(config: &Config, cli: &Tunnel) -> anyhow::Result<i32> {
+    if let Some(action) = &cli.action {
+        if let Some(profile) = shadowed_profile(config, action) {
+            log::warn!(
+                "Running the `{0}` subcommand, the tunnel profile `{0}` can only be launched as \
+                part of a group (or renamed)",
+                profile.name
+            );
+        }
+        return launch_tunnel_action(config, action);
+    }
+    let name = cli.name.as_deref().unwrap();
+    if let Some(group) = name.strip_prefix('@') {
+        return launch_group(config, cli, group);
+    }
+    let profile = select_profile_by_name("Tunnel", &config.tunnels, name, !cli.background)?;
     if profile.forwards.is_empty() {
         bail!("Profile doesn't contain any forwards");
     }
-    let mut ssh_args = ssh_args(config, &cli.common, &profile.ssh_profile, false)?;
+    if cli.install_systemd {
+        return install_systemd(profile, &cli.common, cli.socket);
+    }
+    if cli.uninstall_systemd {
+        return uninstall_systemd(profile);
+    }
+    if cli.env {
+        return print_env(profile, cli.shell);
+    }
+    let target = match profile.transport {
+        TunnelTransport::Ssh => Some(ssh_target(
+            config,
+            &cli.common,
+            profile.ssh_profile_name()?,
+            false,
+        )?),
+        TunnelTransport::Kubectl => None,
+    };
+    if cli.background {
+        // The SSH profile has been resolved first so that config errors are reported here
+        return start_background(profile);
+    }
+    let activated = activated_listeners();
+    // systemd has already bound the ports of a socket activated tunnel
+    let forwards = if activated.is_empty() {
+        choose_local_ports(&profile.forwards)?
+    } else {
+        profile.forwards.clone()
+    };
+    log_forwards(&forwards);
+
+    // Printed and exec tunnels connect to ssh directly
+    let relay = if cli.common.stdout || cli.exec {
+        None
+    } else {
+        Relay::for_profile(profile, &forwards, activated)?
+    };
+    let ssh_forwards = match &relay {
+        Some(relay) => relay.ssh_forwards(&forwards),
+        None => forwards.clone(),
+    };
+    let transport = match target {
+        Some(mut target) => {
+            if relay.is_some() {
+                if let SshBackend::Native = target.backend {
+                    bail!("The native SSH backend can't relay tunnels");
+                }
+            }
+            target.options.extend(forward_args(&ssh_forwards));
+            target.options.extend(connection_args(profile));
+            Transport::Ssh(target)
+        }
+        None => Transport::Kubectl(kubectl_args(profile, &ssh_forwards)?),
+    };
+    if cli.common.stdout {
+        return match transport {
+            Transport::Ssh(target) => invoke_ssh(target, Vec::new(), true),
+            Transport::Kubectl(args) => print_kubectl(args),
+        };
+    }
+    if cli.exec {
+        // Exec tunnels aren't registered, so they can run alongside the profile's usual tunnel
+        return exec_tunnel(transport, &forwards, cli.command.clone());
+    }
+
+    register(profile, &forwards)?;
+    let result = serve_pac(profile, &forwards)
+        .and_then(|_| run_tunnel(transport, profile, &forwards, relay.as_ref()));
+    unregister(profile);
+    result
+}
+
+impl TunnelProfile {
+    /// The SSH profile of a tunnel that uses the `ssh` transport
+    pub fn ssh_profile_name(&self) -> anyhow::Result<&str> {
+        self.ssh_profile
+            .as_deref()
+            .with_context(|| format!("Tunnel `{}` needs an `ssh_profile`", self.name))
+    }
+}
+
+/// What carries a tunnel's forwards
+enum Transport<'a> {
+    Ssh(SshTarget<'a>),
+    /// The arguments for `kubectl port-forward`
+    Kubectl(Vec<String>),
+}
+
+impl Transport<'_> {
+    fn program(&self) -> &'static str {
+        match self {
+            Transport::Ssh(_) => "ssh",
+            Transport::Kubectl(_) => "kubectl",
+        }
+    }
+
+    fn preflight(&self) -> anyhow::Result<()> {
+        match self {
+            Transport::Ssh(target) => target.preflight(),
+            Transport::Kubectl(_) => Ok(()),
+        }
+    }
+
+    fn spawn(&self) -> anyhow::Result<Child> {
+        match self {
+            Transport::Ssh(target) => spawn_ssh(target.clone(), Vec::new()),
+            Transport::Kubectl(args) => spawn_kubectl(args),
+        }
+    }
+}
+
+pub fn log_forwards(forwards: &[SshForwardArgument]) {
+    for f in forwards {
+        log::info!("Forwards {}", f.description());
+        f.forward.warn_if_exposed();
+    }
+}
 
-    for f in &profile.forwards {
+/// The ssh options for the forwards
+pub fn forward_args(forwards: &[SshForwardArgument]) -> Vec<String> {
+    forwards.iter().flat_map(|f| f.forward.ssh_args()).collect()
+}
+
+/// The ssh options for a connection that only forwards ports
+pub fn connection_args(profile: &TunnelProfile) -> Vec<String> {
+    vec![
+        "-N".to_string(),
+        "-o".to_string(),
+        "ExitOnForwardFailure=yes".to_string(),
+        "-o".to_string(),
+        format!(
+            "ServerAliveInterval={}",
+            profile
+                .server_alive_interval
+                .unwrap_or(DEFAULT_SERVER_ALIVE_INTERVAL)
+        ),
+        "-o".to_string(),
+        format!(
+            "ServerAliveCountMax={}",
+            profile
+                .server_alive_count_max
+                .unwrap_or(DEFAULT_SERVER_ALIVE_COUNT_MAX)
+        ),
+    ]
+}
+
+/// Runs the tunnel, reconnecting when it drops if the profile has a reconnect policy
+fn run_tunnel(
+    transport: Transport,
+    profile: &TunnelProfile,
+    forwards: &[SshForwardArgument],
+    relay: Option<&Relay>,
+) -> anyhow::Result<i32> {
+    transport.preflight()?;
+    let ssh_forwards = match relay {
+        Some(relay) => relay.ssh_forwards(forwards),
+        None => forwards.to_vec(),
+    };
+    let Some(policy) = &profile.reconnect else {
+        return connect(&transport, &ssh_forwards, relay, || {
+            ready(profile, forwards)
+        });
+    };
+    let mut connected = false;
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let mut came_up = false;
+        let result = connect(&transport, &ssh_forwards, relay, || {
+            came_up = true;
+            if connected {
+                log::info!("Tunnel `{}` reconnected", profile.name);
+            } else {
+                ready(profile, forwards);
+            }
+        });
+        let reason = match &result {
+            // ssh only exits successfully when it was asked to
+            Ok(0) => return result,
+            Ok(code) => format!("{} exited with {code}", transport.program()),
+            // Don't keep retrying a tunnel that has never worked
+            Err(_) if !connected && !came_up => return result,
+            Err(e) => format!("{e:#}"),
+        };
+        if came_up {
+            connected = true;
+            failures = 0;
+            log::warn!(
+                "Tunnel `{}` dropped after {}: {reason}",
+                profile.name,
+                format_duration(started.elapsed())
+            );
+        } else {
+            log::warn!("Unable to reconnect tunnel `{}`: {reason}", profile.name);
+        }
+        failures += 1;
+        if let Some(max) = policy.max_attempts {
+            if failures > max {
+                bail!(
+                    "Giving up on tunnel `{}` after {max} attempts to reconnect",
+                    profile.name
+                );
+            }
+        }
+        let delay = policy.delay(failures);
         log::info!(
-            "Forwards {}:{} -> local port {}",
-            f.remote_host,
-            f.remote_port,
-            f.local_port
+            "Reconnecting tunnel `{}` in {:.1}s (attempt {failures})",
+            profile.name,
+            delay.as_secs_f64()
         );
-        ssh_args.push("-L".to_string());
-        ssh_args.push(f.ssh_arg());
-    }
-    ssh_args.append(
-        &mut ["sleep", "2147483647"]
-            .into_iter()
-            .map(str::to_string)
-            .collect(),
-    );
-
-    if let Some(o) = profile.open.as_ref().map(String::to_string) {
-        std::thread::spawn(move || {
-            sleep(Duration::from_secs(1));
-            log::info!("Opening: {}", o);
-            if let Err(e) = open::that(&o) {
-                log::error!("Unable to open: {o}: {e}");
+        sleep(delay);
+    }
+}
+
+/// Runs a local command while the tunnel is up, returning its exit code
+fn exec_tunnel(
+    transport: Transport,
+    forwards: &[SshForwardArgument],
+    command: Vec<String>,
+) -> anyhow::Result<i32> {
+    transport.preflight()?;
+    let env = tunnel_env(forwards)?;
+    let mut command = command.into_iter();
+    let program = command.next().unwrap();
+    let args = command.collect::<Vec<_>>();
+    let transport = match transport {
+        Transport::Ssh(mut target) => {
+            if let SshBackend::Native = target.backend {
+                return exec_native(target, forwards, program, args, env);
             }
-        });
+            // Leave the terminal's input to the command
+            target.options.push("-n".to_string());
+            Transport::Ssh(target)
+        }
+        transport => transport,
+    };
+    let mut child = transport.spawn()?;
+    let result = wait_until_ready(forwards, &mut child, transport.program())
+        .and_then(|_| invoke(&program, args, env, false));
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
+
+/// Runs a local command while the native SSH backend forwards the ports
+fn exec_native(
+    target: SshTarget,
+    forwards: &[SshForwardArgument],
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+) -> anyhow::Result<i32> {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "native-ssh")] {
+            // The forwarding loop never returns, so the command's thread exits the process
+            crate::native::forward(target, forwards, move || {
+                std::thread::spawn(move || {
+                    let code = invoke(&program, args, env, false).unwrap_or_else(|e| {
+                        log::error!("{e:#}");
+                        crate::exit::error_code(&e)
+                    });
+                    std::process::exit(code);
+                });
+            })
+        } else {
+            let _ = (target, forwards, program, args, env);
+            Err(crate::ssh::native_unavailable())
+        }
     }
+}
 
-    invoke_ssh(ssh_args, cli.common.stdout)?;
+/// Environment variables with the forwards' local ports, followed by those of their `env` templates
+fn tunnel_env(forwards: &[SshForwardArgument]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut env = port_env(forwards);
+    for (i, f) in forwards.iter().enumerate() {
+        for (name, template) in &f.env {
+            if name.is_empty()
+                || name.starts_with(|c: char| c.is_ascii_digit())
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!("`{name}` isn't a valid environment variable name");
+            }
+            let value = expand_template(template, forwards, i)
+                .with_context(|| format!("Invalid template for `{name}`"))?;
+            env.push((name.clone(), value));
+        }
+    }
+    Ok(env)
+}
+
+/// Prints the environment variables of a running tunnel, as commands for the shell to evaluate
+fn print_env(profile: &TunnelProfile, shell: Shell) -> anyhow::Result<i32> {
+    let Some(state) = running_state(&profile.name)? else {
+        log::warn!("Tunnel `{}` isn't running", profile.name);
+        return Ok(1);
+    };
+    for (name, value) in tunnel_env(&state.forwards)? {
+        println!("{}", shell.export(&name, &value));
+    }
+    Ok(0)
+}
+
+/// Prints the environment variables of a group's running tunnels. A variable that more than one
+/// of them sets (such as `REMOTEC_PORT`) is taken from the last.
+pub fn print_group_env(members: &[&TunnelProfile], shell: Shell) -> anyhow::Result<i32> {
+    let mut running = Vec::new();
+    let mut code = 0;
+    for member in members {
+        match running_state(&member.name)? {
+            Some(state) => running.push((member.name.as_str(), state.forwards)),
+            None => {
+                log::warn!("Tunnel `{}` isn't running", member.name);
+                code = 1;
+            }
+        }
+    }
+    for (name, value) in group_env(&running)? {
+        println!("{}", shell.export(&name, &value));
+    }
+    Ok(code)
+}
+
+fn group_env(tunnels: &[(&str, Vec<SshForwardArgument>)]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut env: Vec<(String, String)> = Vec::new();
+    let mut set_by = HashMap::new();
+    for (tunnel, forwards) in tunnels {
+        for (name, value) in tunnel_env(forwards)? {
+            if let Some(previous) = set_by.insert(name.clone(), tunnel) {
+                log::warn!(
+                    "`{name}` is set by both `{previous}` and `{tunnel}`, using `{tunnel}`'s"
+                );
+                env.retain(|(n, _)| *n != name);
+            }
+            env.push((name, value));
+        }
+    }
+    Ok(env)
+}
+
+/// The shells that `--env` can print commands for
+#[derive(ValueEnum, Copy, Clone, Default)]
+pub enum Shell {
+    /// bash, zsh or any other POSIX shell
+    #[default]
+    Bash,
+    Fish,
+    Powershell,
+}
+
+impl Shell {
+    fn export(self, name: &str, value: &str) -> String {
+        match self {
+            Shell::Bash => format!("export {name}='{}'", value.replace('\'', "'\\''")),
+            Shell::Fish => format!(
+                "set -gx {name} '{}'",
+                value.replace('\\', "\\\\").replace('\'', "\\'")
+            ),
+            Shell::Powershell => format!("$env:{name} = '{}'", value.replace('\'', "''")),
+        }
+    }
+}
+
+/// Environment variables with the forwards' local ports: `REMOTEC_PORT_<N>` (counting from 0),
+/// `REMOTEC_PORT_<NAME>` for named forwards, and `REMOTEC_PORT` for the first forward
+fn port_env(forwards: &[SshForwardArgument]) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    for (i, f) in forwards.iter().enumerate() {
+        let Some(port) = f.forward.local_port() else {
+            continue;
+        };
+        if i == 0 {
+            env.push(("REMOTEC_PORT".to_string(), port.to_string()));
+        }
+        env.push((format!("REMOTEC_PORT_{i}"), port.to_string()));
+        if let Some(name) = &f.name {
+            let name = file_name(name).to_uppercase();
+            env.push((format!("REMOTEC_PORT_{name}"), port.to_string()));
+        }
+    }
+    env
+}
+
+/// Makes a single connection, returning the exit code once it closes (or 0 if the relay closes it
+/// for being idle)
+fn connect(
+    transport: &Transport,
+    forwards: &[SshForwardArgument],
+    relay: Option<&Relay>,
+    on_ready: impl FnOnce(),
+) -> anyhow::Result<i32> {
+    if let Transport::Ssh(target) = transport {
+        if let SshBackend::Native = target.backend {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "native-ssh")] {
+                    return crate::native::forward(target.clone(), forwards, on_ready);
+                } else {
+                    return Err(crate::ssh::native_unavailable());
+                }
+            }
+        }
+    }
+    let program = transport.program();
+    let mut child = transport.spawn()?;
+    let result = wait_until_ready(forwards, &mut child, program).and_then(|_| {
+        on_ready();
+        let Some(relay) = relay else {
+            let status = child
+                .wait()
+                .with_context(|| format!("Error waiting for {program}"))?;
+            return Ok(status_code(status));
+        };
+        relay.start();
+        loop {
+            if let Some(status) = child
+                .try_wait()
+                .with_context(|| format!("Error waiting for {program}"))?
+            {
+                return Ok(status_code(status));
+            }
+            if relay.expired() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(0);
+            }
+            sleep(POLL_INTERVAL);
+        }
+    });
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    result
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before a reconnect attempt (counting from 1)
+    fn delay(&self, attempt: u32) -> Duration {
+        let initial = self.initial_delay.unwrap_or(DEFAULT_INITIAL_DELAY);
+        let max = self.max_delay.unwrap_or(DEFAULT_MAX_DELAY);
+        let jitter = self.jitter.unwrap_or(DEFAULT_JITTER).clamp(0.0, 1.0);
+        let delay = (initial * 2f64.powi(attempt.min(32) as i32 - 1)).min(max);
+        let factor = 1.0 + jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((delay * factor).max(0.0))
+    }
+}
+
+/// Chooses free ports for the forwards set to `auto`, and checks that the fixed ports are free
+pub fn choose_local_ports(
+    forwards: &[SshForwardArgument],
+) -> anyhow::Result<Vec<SshForwardArgument>> {
+    let mut forwards = forwards.to_vec();
+    // Keep the ports bound until they have all been chosen, so that they are distinct
+    let mut listeners = Vec::new();
+    let mut conflicts = Vec::new();
+    for f in &mut forwards {
+        match f.forward.local_listen() {
+            Some(ForwardListen::Port { bind_address, port }) => {
+                let address = listen_address(bind_address.as_deref());
+                match TcpListener::bind((address, port)) {
+                    Ok(listener) => {
+                        f.forward.set_local_port(listener.local_addr()?.port());
+                        listeners.push(listener);
+                    }
+                    Err(_) if port == 0 => {
+                        bail!("Unable to find a free port on {address}");
+                    }
+                    Err(_) => {
+                        let alternative = (port.saturating_add(1)..=port.saturating_add(100))
+                            .find(|p| TcpListener::bind((address, *p)).is_ok())
+                            .map(|p| format!("{p} or "))
+                            .unwrap_or_default();
+                        conflicts.push(format!(
+                            "Local port {port} (for {}) is already in use, \
+                            try {alternative}\"auto\"",
+                            f.description()
+                        ));
+                    }
+                }
+            }
+            Some(ForwardListen::Socket(path)) if Path::new(&path).exists() => {
+                conflicts.push(format!(
+                    "Local socket {path} (for {}) already exists",
+                    f.description()
+                ));
+            }
+            _ => {}
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(conflicts.join("\n"));
+    }
+    Ok(forwards)
+}
+
+/// Polls the forwards' local ports (and sockets) until they all accept connections, or ssh exits
+/// or the timeout passes (leaving the caller to stop it).
+///
+/// Remote forwards can't be checked from here, but ssh exits if they fail.
+pub fn wait_until_ready(
+    forwards: &[SshForwardArgument],
+    child: &mut Child,
+    program: &str,
+) -> anyhow::Result<()> {
+    wait_until_ready_within(forwards, child, program, READY_TIMEOUT)
+}
+
+fn wait_until_ready_within(
+    forwards: &[SshForwardArgument],
+    child: &mut Child,
+    program: &str,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut pending = forwards
+        .iter()
+        .filter_map(|f| Some((f, f.forward.local_listen()?)))
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    while !pending.is_empty() {
+        if start.elapsed() > timeout {
+            bail!(
+                "Forward {} didn't come up within {} seconds",
+                pending[0].0.description(),
+                timeout.as_secs()
+            );
+        }
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("Error waiting for {program}"))?
+        {
+            bail!(
+                "{program} exited ({}) before forward {} came up",
+                status_code(status),
+                pending[0].0.description()
+            );
+        }
+        pending.retain(|(_, listen)| !accepts_connections(listen));
+        if !pending.is_empty() {
+            sleep(POLL_INTERVAL);
+        }
+    }
     Ok(())
 }
+
+fn accepts_connections(listen: &ForwardListen) -> bool {
+    match listen {
+        ForwardListen::Port { bind_address, port } => {
+            let address = match listen_address(bind_address.as_deref()) {
+                "0.0.0.0" => "127.0.0.1",
+                "::" => "::1",
+                address => address,
+            };
+            let Some(address) = (address, *port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+            else {
+                return false;
+            };
+            TcpStream::connect_timeout(&address, POLL_INTERVAL).is_ok()
+        }
+        #[cfg(unix)]
+        ForwardListen::Socket(path) => std::os::unix::net::UnixStream::connect(path).is_ok(),
+        #[cfg(not(unix))]
+        ForwardListen::Socket(path) => Path::new(path).exists(),
+    }
+}
+
+/// Announces that the tunnel is ready, by opening the configured locations or printing "ready"
+fn ready(profile: &TunnelProfile, forwards: &[SshForwardArgument]) {
+    if let Err(e) = mark_ready(profile) {
+        log::warn!("Unable to record that the tunnel is ready: {e:#}");
+    }
+    if profile.open.is_empty() {
+        println!("ready");
+    }
+    open_locations(profile, forwards);
+}
+
+/// Opens the profile's `open` locations
+pub fn open_locations(profile: &TunnelProfile, forwards: &[SshForwardArgument]) {
+    for template in &profile.open {
+        let location = match expand_template(template, forwards, 0) {
+            Ok(location) => location,
+            Err(e) => {
+                log::error!("Unable to open `{template}`: {e:#}");
+                continue;
+            }
+        };
+        log::info!("Opening: {}", location);
+        let result = match &profile.open_with {
+            Some(app) => open::with(&location, app),
+            None => open::that(&location),
+        };
+        if let Err(e) = result {
+            log::error!("Unable to open: {location}: {e}");
+        }
+    }
+}
+
+/// Fills in the placeholders in an `open` or `env` template:
+///
+/// - `{forwards[N].local_port}` (or `{port.N}`) is the local port of the Nth forward, from 0
+/// - `{port}` is the local port of the `current` forward (the first, for `open` templates)
+/// - `{forward:NAME}` is the local address (`host:port` or socket path) of the named forward
+fn expand_template(
+    template: &str,
+    forwards: &[SshForwardArgument],
+    current: usize,
+) -> anyhow::Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .context("Unclosed `{`")?;
+        expanded.push_str(&placeholder(&rest[start + 1..end], forwards, current)?);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn placeholder(
+    key: &str,
+    forwards: &[SshForwardArgument],
+    current: usize,
+) -> anyhow::Result<String> {
+    if let Some(name) = key.strip_prefix("forward:") {
+        let listen = forwards
+            .iter()
+            .find(|f| f.name.as_deref() == Some(name))
+            .with_context(|| format!("No forward is named `{name}`"))?
+            .forward
+            .local_listen()
+            .with_context(|| format!("Forward `{name}` doesn't listen on this machine"))?;
+        return Ok(listen.description());
+    }
+    let index = match key {
+        "port" => Some(current),
+        _ => key
+            .strip_prefix("port.")
+            .or_else(|| {
+                key.strip_prefix("forwards[")
+                    .and_then(|k| k.strip_suffix("].local_port"))
+            })
+            .and_then(|i| i.parse::<usize>().ok()),
+    };
+    let index = index.with_context(|| format!("Unknown placeholder `{{{key}}}`"))?;
+    forwards
+        .get(index)
+        .with_context(|| format!("There is no forward {index}"))?
+        .forward
+        .local_port()
+        .map(|port| port.to_string())
+        .with_context(|| format!("Forward {index} doesn't listen on a local port"))
+}
+
+/// A tunnel profile with the same name as the subcommand, which is run instead of the tunnel
+fn shadowed_profile<'a>(config: &'a Config, action: &TunnelAction) -> Option<&'a TunnelProfile> {
+    let subcommand = match action {
+        TunnelAction::List => "list",
+        TunnelAction::Ports { .. } => "ports",
+        TunnelAction::Stop { .. } => "stop",
+    };
+    config.tunnels.iter().find(|t| t.name == subcommand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local forward on a port that nothing is listening on
+    fn unused_forward() -> Vec<SshForwardArgument> {
+        let port = TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let forward = serde_json::json!({"local_port": port, "remote_port": 80});
+        vec![serde_json::from_value(forward).unwrap()]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ready_times_out() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let timeout = Duration::from_millis(500);
+        let result = wait_until_ready_within(&unused_forward(), &mut child, "sleep", timeout);
+        child.kill().unwrap();
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("didn't come up within"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ready_once_listening() {
+        let forwards = unused_forward();
+        let port = forwards[0].forward.local_port().unwrap();
+        let _listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        let result = wait_until_ready_within(&forwards, &mut child, "sleep", timeout);
+        child.kill().unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn auto_ports_are_distinct() {
+        let forwards: Vec<SshForwardArgument> = serde_json::from_value(serde_json::json!([
+            {"local_port": "auto", "remote_port": 80},
+            {"local_port": 0, "remote_port": 81},
+        ]))
+        .unwrap();
+        let chosen = choose_local_ports(&forwards).unwrap();
+        let ports = chosen
+            .iter()
+            .map(|f| f.forward.local_port().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(ports[0], 0);
+        assert_ne!(ports[1], 0);
+        assert_ne!(ports[0], ports[1]);
+    }
+
+    #[test]
+    fn port_in_use() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let forward = serde_json::json!({"local_port": port, "remote_port": 80});
+        let Err(error) = choose_local_ports(&[serde_json::from_value(forward).unwrap()]) else {
+            panic!("Port {port} should be in use");
+        };
+        let error = error.to_string();
+        assert!(
+            error.contains(&format!("Local port {port} (for ")),
+            "{error}"
+        );
+        assert!(error.contains("is already in use, try "), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ready_fails_when_program_exits() {
+        let mut child = std::process::Command::new("false").spawn().unwrap();
+        let timeout = Duration::from_secs(10);
+        let result = wait_until_ready_within(&unused_forward(), &mut child, "false", timeout);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("false exited (1)"), "{error}");
+    }
+
+    fn template_forwards() -> Vec<SshForwardArgument> {
+        serde_json::from_value(serde_json::json!([
+            {"local_port": 8080, "remote_port": 80},
+            {"name": "db", "local_port": 5432, "remote_port": 5432},
+            {"type": "remote", "remote_port": 9000, "local_port": 3000},
+            {"name": "docker", "local_socket": "/tmp/docker.sock", "remote_socket": "/run/docker.sock"},
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn known_placeholders() {
+        let forwards = template_forwards();
+        let expand = |template| expand_template(template, &forwards, 1).unwrap();
+        assert_eq!(expand("http://localhost:{port}/"), "http://localhost:5432/");
+        assert_eq!(expand("{port.0}"), "8080");
+        assert_eq!(expand("{forwards[0].local_port}"), "8080");
+        assert_eq!(
+            expand("postgres://{forward:db}/app"),
+            "postgres://localhost:5432/app"
+        );
+        assert_eq!(expand("unix://{forward:docker}"), "unix:///tmp/docker.sock");
+        assert_eq!(expand("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        let forwards = template_forwards();
+        let expand = |template| {
+            expand_template(template, &forwards, 0)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(expand("{host}"), "Unknown placeholder `{host}`");
+        assert_eq!(expand("{port.x}"), "Unknown placeholder `{port.x}`");
+        assert_eq!(expand("{port.9}"), "There is no forward 9");
+        assert_eq!(expand("{forward:web}"), "No forward is named `web`");
+        assert_eq!(
+            expand("{port.2}"),
+            "Forward 2 doesn't listen on a local port"
+        );
+    }
+
+    #[test]
+    fn unterminated_braces() {
+        let forwards = template_forwards();
+        let error = expand_template("http://localhost:{port", &forwards, 0).unwrap_err();
+        assert_eq!(error.to_string(), "Unclosed `{`");
+    }
+
+    #[test]
+    fn subcommand_names() {
+        let config = crate::config::fixtures::config(serde_json::json!({"tunnels": [
+            {"name": "db", "ssh_profile": "s", "forwards": []},
+            {"name": "list", "ssh_profile": "s", "forwards": []}
+        ]}));
+        let shadowed = shadowed_profile(&config, &TunnelAction::List).unwrap();
+        assert_eq!(shadowed.name, "list");
+        let ports = TunnelAction::Ports {
+            name: "db".to_string(),
+        };
+        assert!(shadowed_profile(&config, &ports).is_none());
+    }
+
+    #[test]
+    fn group_variables() {
+        let forwards = |json| serde_json::from_value::<Vec<SshForwardArgument>>(json).unwrap();
+        let db = forwards(serde_json::json!([
+            {"name": "db", "local_port": 5432, "remote_port": 5432,
+             "env": {"DATABASE_URL": "postgres://localhost:{port}/app"}}
+        ]));
+        let web =
+            forwards(serde_json::json!([{"name": "web", "local_port": 8080, "remote_port": 80}]));
+        let env = group_env(&[("db", db), ("web", web)]).unwrap();
+        let env = env
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            env,
+            [
+                "REMOTEC_PORT_DB=5432",
+                "DATABASE_URL=postgres://localhost:5432/app",
+                // Both tunnels set these, so they're the last tunnel's
+                "REMOTEC_PORT=8080",
+                "REMOTEC_PORT_0=8080",
+                "REMOTEC_PORT_WEB=8080",
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Runs a kubectl tunnel against a fake `kubectl` on the PATH, which is this test binary run again
+//! with `FAKE_KUBECTL_LOG` set
+
+#![cfg(unix)]
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Pretends to be `kubectl port-forward`: records the arguments, then listens on the local ports
+#[test]
+fn fake_kubectl() {
+    let Ok(log) = std::env::var("FAKE_KUBECTL_LOG") else {
+        return;
+    };
+    let args = std::env::var("FAKE_KUBECTL_ARGS").unwrap();
+    std::fs::write(log, &args).unwrap();
+    // Wait a moment, as kubectl does while connecting to the cluster
+    std::thread::sleep(Duration::from_millis(500));
+    let listeners = args
+        .split(' ')
+        .filter_map(|a| a.split_once(':'))
+        .map(|(local, _)| TcpListener::bind(("127.0.0.1", local.parse().unwrap())).unwrap())
+        .collect::<Vec<_>>();
+    for stream in listeners[0].incoming() {
+        drop(stream);
+    }
+}
+
+fn write_config(path: &Path, port: u16) {
+    let config = serde_json::json!({
+        "tunnels": [{
+            "name": "grafana",
+            "transport": "kubectl",
+            "context": "prod",
+            "namespace": "monitoring",
+            "target": "svc/grafana",
+            "forwards": [{"local_port": port, "remote_port": 3000}]
+        }]
+    });
+    std::fs::write(path, config.to_string()).unwrap();
+}
+
+fn write_fake_kubectl(bin: &Path, log: &Path) {
+    std::fs::create_dir_all(bin).unwrap();
+    let path = bin.join("kubectl");
+    let mut script = std::fs::File::create(&path).unwrap();
+    writeln!(
+        script,
+        "#!/bin/sh\n\
+        FAKE_KUBECTL_LOG='{}' FAKE_KUBECTL_ARGS=\"$*\" exec '{}' --exact fake_kubectl >/dev/null",
+        log.display(),
+        std::env::current_exe().unwrap().display()
+    )
+    .unwrap();
+    drop(script);
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn kubectl_tunnel_runs_command_once_ready() {
+    let home = tempfile::tempdir().unwrap();
+    let port = TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = home.path().join("config.json");
+    write_config(&config, port);
+    let log = home.path().join("kubectl.log");
+    let bin = home.path().join("bin");
+    write_fake_kubectl(&bin, &log);
+
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    let output = Command::new(env!("CARGO_BIN_EXE_remotec"))
+        .args(["tunnel", "grafana", "--exec", "--"])
+        .args(["sh", "-c", "echo \"ready on $REMOTEC_PORT_0\""])
+        .env("REMOTEC_CONFIG", &config)
+        .env("HOME", home.path())
+        .env("XDG_RUNTIME_DIR", home.path())
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("ready on {port}\n")
+    );
+    assert_eq!(
+        std::fs::read_to_string(log).unwrap(),
+        format!("port-forward --context prod --namespace monitoring svc/grafana {port}:3000")
+    );
+}
@@ -0,0 +1,286 @@
+//! Runs the native SSH backend against an in-process SSH server, which runs commands on this
+//! machine with `sh`
+
+#![cfg(all(unix, feature = "native-ssh"))]
+
+mod server;
+
+use serde_json::{json, Value};
+use server::{Server, PASSWORD, USERNAME};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// Connects to the forward given in `REMOTEC_PORT_0`, and records what the server sends back
+#[test]
+fn forward_client() {
+    let Ok(log) = std::env::var("FORWARD_CLIENT_LOG") else {
+        return;
+    };
+    let port = std::env::var("REMOTEC_PORT_0").unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port.parse().unwrap())).unwrap();
+    stream.write_all(b"hello").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    std::fs::write(log, reply).unwrap();
+}
+
+/// An SSH profile for the server, logging in with the password
+fn profile(server: &Server) -> Value {
+    json!({
+        "name": "test",
+        "hostname": "127.0.0.1",
+        "port": server.port,
+        "username": USERNAME,
+        "host_keys": [server.host_key()],
+        "password_command": ["printf", "%s\n", PASSWORD],
+    })
+}
+
+/// Writes a config with the SSH profile and a `connected` command that runs on it, replacing any
+/// of its sections that are in `others`
+fn write_config(path: &Path, ssh: Value, others: Value) {
+    let connected = json!({
+        "name": "connected",
+        "ssh_profile": "test",
+        "command": ["echo", "connected"]
+    });
+    let mut config = json!({
+        "ssh_defaults": {"backend": "Native"},
+        "ssh": [ssh],
+        "commands": [connected]
+    });
+    config
+        .as_object_mut()
+        .unwrap()
+        .extend(others.as_object().unwrap().clone());
+    std::fs::write(path, config.to_string()).unwrap();
+}
+
+/// Runs remotec with the config, and without any of the user's keys or agent
+fn remotec(home: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_remotec"))
+        .args(args)
+        .env("REMOTEC_CONFIG", home.join("config.json"))
+        .env("HOME", home)
+        .env("XDG_RUNTIME_DIR", home)
+        .env_remove("SSH_AUTH_SOCK")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if started.elapsed() > Duration::from_secs(30) {
+            child.kill().unwrap();
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn assert_success(output: &Output) {
+    assert!(output.status.success(), "{}", stderr(output));
+}
+
+#[test]
+fn exec_keeps_quoting() {
+    let server = Server::start(1);
+    let home = tempfile::tempdir().unwrap();
+    let command = json!({
+        "name": "words",
+        "ssh_profile": "test",
+        "command": ["printf", "'%s|'", "{word}", "$HOME"],
+        "parameters": [{"name": "word"}]
+    });
+    write_config(
+        &home.path().join("config.json"),
+        profile(&server),
+        json!({"commands": [command]}),
+    );
+    let output = remotec(home.path(), &["command", "words", "a  'b'; c"], "");
+    assert_success(&output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let home_dir = std::env::var("HOME").unwrap_or_default();
+    assert_eq!(stdout, format!("a  'b'; c|{home_dir}|"));
+}
+
+#[test]
+fn exec_exit_status_and_stderr() {
+    let server = Server::start(1);
+    let home = tempfile::tempdir().unwrap();
+    let command = json!({
+        "name": "fail",
+        "ssh_profile": "test",
+        "command": ["echo", "oops", ">&2;", "exit", "3"]
+    });
+    write_config(
+        &home.path().join("config.json"),
+        profile(&server),
+        json!({"commands": [command]}),
+    );
+    let output = remotec(home.path(), &["command", "fail"], "");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(stderr(&output), "oops\n");
+}
+
+#[test]
+fn shell_reads_stdin() {
+    let server = Server::start(1);
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        &home.path().join("config.json"),
+        profile(&server),
+        json!({}),
+    );
+    let output = remotec(home.path(), &["ssh", "test"], "echo hi\nexit 4\n");
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+}
+
+#[test]
+fn pinned_fingerprint() {
+    let server = Server::start(1);
+    let home = tempfile::tempdir().unwrap();
+    let mut ssh = profile(&server);
+    ssh["host_keys"] = json!([server.fingerprint()]);
+    write_config(&home.path().join("config.json"), ssh, json!({}));
+    let output = remotec(home.path(), &["command", "connected"], "");
+    assert_success(&output);
+}
+
+#[test]
+fn pin_mismatch() {
+    let server = Server::start(1);
+    let other = Server::start(2);
+    let home = tempfile::tempdir().unwrap();
+    let mut ssh = profile(&server);
+    ssh["host_keys"] = json!([other.host_key()]);
+    write_config(&home.path().join("config.json"), ssh, json!({}));
+    let output = remotec(home.path(), &["command", "connected"], "");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).contains(&format!(
+            "Host key for 127.0.0.1 ({}) doesn't match its pinned keys",
+            server.fingerprint()
+        )),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn wrong_password() {
+    let server = Server::start(1);
+    let home = tempfile::tempdir().unwrap();
+    let mut ssh = profile(&server);
+    ssh["password_command"] = json!(["echo", "wrong"]);
+    write_config(&home.path().join("config.json"), ssh, json!({}));
+    let output = remotec(home.path(), &["command", "connected"], "");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).contains("Authentication failed for tester@127.0.0.1"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn jump_host() {
+    let jump = Server::start(1);
+    let server = Server::start(2);
+    let home = tempfile::tempdir().unwrap();
+    let mut ssh = profile(&server);
+    ssh["jump_hosts"] = json!([{
+        "hostname": "127.0.0.1",
+        "port": jump.port,
+        "host_keys": [jump.host_key()]
+    }]);
+    write_config(&home.path().join("config.json"), ssh, json!({}));
+    let output = remotec(home.path(), &["command", "connected"], "");
+    assert_success(&output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "connected\n");
+    assert_eq!(jump.forwarded(), [format!("127.0.0.1:{}", server.port)]);
+}
+
+#[test]
+fn jump_host_pin_mismatch() {
+    let jump = Server::start(1);
+    let server = Server::start(2);
+    let home = tempfile::tempdir().unwrap();
+    let mut ssh = profile(&server);
+    ssh["jump_hosts"] = json!([{
+        "hostname": "127.0.0.1",
+        "port": jump.port,
+        "host_keys": [server.host_key()]
+    }]);
+    write_config(&home.path().join("config.json"), ssh, json!({}));
+    let output = remotec(home.path(), &["command", "connected"], "");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).contains(&format!(
+            "Host key for 127.0.0.1 ({}) doesn't match its pinned keys",
+            jump.fingerprint()
+        )),
+        "{}",
+        stderr(&output)
+    );
+    assert!(jump.forwarded().is_empty());
+}
+
+#[test]
+fn local_forward() {
+    let server = Server::start(1);
+    let echo = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut stream, _) = echo.accept().unwrap();
+        let mut request = String::new();
+        stream.read_to_string(&mut request).unwrap();
+        stream.write_all(request.to_uppercase().as_bytes()).unwrap();
+    });
+    let home = tempfile::tempdir().unwrap();
+    let tunnel = json!({
+        "name": "echo",
+        "ssh_profile": "test",
+        "forwards": [{"local_port": "auto", "remote_host": "127.0.0.1", "remote_port": echo_port}]
+    });
+    write_config(
+        &home.path().join("config.json"),
+        profile(&server),
+        json!({"tunnels": [tunnel]}),
+    );
+    let log = home.path().join("forward.log");
+    let helper = std::env::current_exe().unwrap();
+    let script = format!(
+        "FORWARD_CLIENT_LOG='{}' exec '{}' --exact forward_client >/dev/null",
+        log.display(),
+        helper.display()
+    );
+    let output = remotec(
+        home.path(),
+        &["tunnel", "echo", "--exec", "--", "sh", "-c", &script],
+        "",
+    );
+    assert_success(&output);
+    assert_eq!(std::fs::read_to_string(log).unwrap(), "HELLO");
+    assert_eq!(server.forwarded(), [format!("127.0.0.1:{echo_port}")]);
+}
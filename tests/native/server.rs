@@ -0,0 +1,676 @@
+//! A minimal SSH server for testing the native client against. It implements only what libssh2
+//! needs to connect (curve25519-sha256 key exchange, an ed25519 host key, aes128-ctr with
+//! hmac-sha2-256, and password authentication), runs `exec` and `shell` requests with `sh`, and
+//! connects `direct-tcpip` channels.
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const USERNAME: &str = "tester";
+pub const PASSWORD: &str = "secret";
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha256 = Hmac<Sha256>;
+
+const MSG_DISCONNECT: u8 = 1;
+const MSG_SERVICE_REQUEST: u8 = 5;
+const MSG_SERVICE_ACCEPT: u8 = 6;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+const MSG_USERAUTH_REQUEST: u8 = 50;
+const MSG_USERAUTH_FAILURE: u8 = 51;
+const MSG_USERAUTH_SUCCESS: u8 = 52;
+const MSG_GLOBAL_REQUEST: u8 = 80;
+const MSG_REQUEST_FAILURE: u8 = 82;
+const MSG_CHANNEL_OPEN: u8 = 90;
+const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+const MSG_CHANNEL_DATA: u8 = 94;
+const MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+const MSG_CHANNEL_EOF: u8 = 96;
+const MSG_CHANNEL_CLOSE: u8 = 97;
+const MSG_CHANNEL_REQUEST: u8 = 98;
+const MSG_CHANNEL_SUCCESS: u8 = 99;
+const MSG_CHANNEL_FAILURE: u8 = 100;
+
+const WINDOW: u32 = 2 * 1024 * 1024;
+const MAX_PACKET: u32 = 32768;
+const CHUNK: usize = 16384;
+
+/// A server listening on a local port, on threads that live as long as the test
+pub struct Server {
+    pub port: u16,
+    host_key: SigningKey,
+    /// The `host:port` of every `direct-tcpip` channel opened, in order
+    forwarded: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    /// Starts a server, whose host key is derived from `seed`
+    pub fn start(seed: u8) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host_key = SigningKey::from_bytes(&[seed; 32]);
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let (key, log) = (host_key.clone(), forwarded.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (key, log) = (key.clone(), log.clone());
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream.unwrap(), &key, &log) {
+                        eprintln!("SSH test server connection failed: {e}");
+                    }
+                });
+            }
+        });
+        Self {
+            port,
+            host_key,
+            forwarded,
+        }
+    }
+
+    /// The host key, as it would be pinned in a profile
+    pub fn host_key(&self) -> String {
+        format!("ssh-ed25519 {}", STANDARD.encode(self.key_blob()))
+    }
+
+    /// The host key's fingerprint, as `ssh-keygen -l` prints it
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.key_blob());
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(digest))
+    }
+
+    pub fn forwarded(&self) -> Vec<String> {
+        self.forwarded.lock().unwrap().clone()
+    }
+
+    fn key_blob(&self) -> Vec<u8> {
+        key_blob(&self.host_key)
+    }
+}
+
+fn key_blob(key: &SigningKey) -> Vec<u8> {
+    Encoder::default()
+        .str("ssh-ed25519")
+        .string(key.verifying_key().as_bytes())
+        .0
+}
+
+/// Builds a message out of SSH's data types (RFC 4251 section 5)
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn byte(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn bool(self, value: bool) -> Self {
+        self.byte(value as u8)
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+
+    fn string(mut self, value: &[u8]) -> Self {
+        self = self.u32(value.len() as u32);
+        self.0.extend(value);
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        self.string(value.as_bytes())
+    }
+
+    /// An unsigned integer, given as big-endian bytes
+    fn mpint(self, value: &[u8]) -> Self {
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        let mut value = value[start..].to_vec();
+        if value.first().is_some_and(|b| b & 0x80 != 0) {
+            value.insert(0, 0);
+        }
+        self.string(&value)
+    }
+}
+
+/// Reads SSH's data types from a message
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> u8 {
+        let (value, rest) = self.0.split_first().unwrap();
+        self.0 = rest;
+        *value
+    }
+
+    fn bool(&mut self) -> bool {
+        self.byte() != 0
+    }
+
+    fn u32(&mut self) -> u32 {
+        let (value, rest) = self.0.split_at(4);
+        self.0 = rest;
+        u32::from_be_bytes(value.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> &'a [u8] {
+        let len = self.u32() as usize;
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        value
+    }
+
+    fn str(&mut self) -> String {
+        String::from_utf8_lossy(self.string()).into_owned()
+    }
+}
+
+/// The cipher and MAC for one direction of the connection, once keys have been exchanged
+struct Keys {
+    cipher: Aes128Ctr,
+    mac_key: Vec<u8>,
+}
+
+impl Keys {
+    fn mac(&self, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.mac_key).unwrap();
+        mac.update(&sequence.to_be_bytes());
+        mac.update(packet);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Sends packets (RFC 4253 section 6), shared by the threads writing to the connection
+struct Sender {
+    stream: TcpStream,
+    keys: Option<Keys>,
+    sequence: u32,
+}
+
+impl Sender {
+    fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let block = if self.keys.is_some() { 16 } else { 8 };
+        let mut padding = block - (payload.len() + 5) % block;
+        if padding < 4 {
+            padding += block;
+        }
+        let mut packet = Encoder::default()
+            .u32((payload.len() + padding + 1) as u32)
+            .byte(padding as u8)
+            .0;
+        packet.extend(payload);
+        packet.extend((0..padding).map(|_| fastrand::u8(..)));
+        if let Some(keys) = &mut self.keys {
+            let mac = keys.mac(self.sequence, &packet);
+            keys.cipher.apply_keystream(&mut packet);
+            packet.extend(mac);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.stream.write_all(&packet)
+    }
+}
+
+struct Receiver {
+    stream: TcpStream,
+    keys: Option<Keys>,
+    sequence: u32,
+}
+
+impl Receiver {
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let block = if self.keys.is_some() { 16 } else { 8 };
+        let mut packet = vec![0; block];
+        self.stream.read_exact(&mut packet)?;
+        if let Some(keys) = &mut self.keys {
+            keys.cipher.apply_keystream(&mut packet);
+        }
+        let length = u32::from_be_bytes(packet[..4].try_into().unwrap()) as usize;
+        let mut rest = vec![0; length + 4 - block];
+        self.stream.read_exact(&mut rest)?;
+        if let Some(keys) = &mut self.keys {
+            keys.cipher.apply_keystream(&mut rest);
+            packet.extend(rest);
+            let mut mac = vec![0; 32];
+            self.stream.read_exact(&mut mac)?;
+            if mac != keys.mac(self.sequence, &packet) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad MAC"));
+            }
+        } else {
+            packet.extend(rest);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        let padding = packet[4] as usize;
+        Ok(packet[5..packet.len() - padding].to_vec())
+    }
+}
+
+fn read_version(stream: &mut TcpStream) -> io::Result<String> {
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while byte[0] != b'\n' {
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if line.starts_with("SSH-") {
+            return Ok(line);
+        }
+    }
+}
+
+fn kexinit() -> Vec<u8> {
+    let cookie = (0..16).map(|_| fastrand::u8(..)).collect::<Vec<_>>();
+    let mut message = Encoder::default().byte(MSG_KEXINIT);
+    message.0.extend(cookie);
+    message
+        .str("curve25519-sha256,curve25519-sha256@libssh.org")
+        .str("ssh-ed25519")
+        .str("aes128-ctr")
+        .str("aes128-ctr")
+        .str("hmac-sha2-256")
+        .str("hmac-sha2-256")
+        .str("none")
+        .str("none")
+        .str("")
+        .str("")
+        .bool(false)
+        .u32(0)
+        .0
+}
+
+/// Receives the next message that isn't part of the transport layer's housekeeping
+fn receive(receiver: &mut Receiver) -> io::Result<Vec<u8>> {
+    loop {
+        let message = receiver.receive()?;
+        match message.first() {
+            // IGNORE, UNIMPLEMENTED, DEBUG
+            Some(2..=4) => continue,
+            Some(&MSG_DISCONNECT) | None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Disconnected",
+                ))
+            }
+            _ => return Ok(message),
+        }
+    }
+}
+
+/// Exchanges keys (RFC 5656 section 4, with curve25519 as in RFC 8731)
+fn exchange_keys(
+    mut stream: TcpStream,
+    host_key: &SigningKey,
+) -> io::Result<(Arc<Mutex<Sender>>, Receiver)> {
+    let server_version = "SSH-2.0-remotec_test";
+    stream.write_all(format!("{server_version}\r\n").as_bytes())?;
+    let client_version = read_version(&mut stream)?;
+    let mut sender = Sender {
+        stream: stream.try_clone()?,
+        keys: None,
+        sequence: 0,
+    };
+    let mut receiver = Receiver {
+        stream,
+        keys: None,
+        sequence: 0,
+    };
+    let server_kexinit = kexinit();
+    sender.send(&server_kexinit)?;
+    let client_kexinit = receive(&mut receiver)?;
+    assert_eq!(client_kexinit[0], MSG_KEXINIT);
+
+    let init = receive(&mut receiver)?;
+    let mut decoder = Decoder(&init);
+    assert_eq!(decoder.byte(), MSG_KEX_ECDH_INIT);
+    let client_public: [u8; 32] = decoder.string().try_into().unwrap();
+    let mut secret = [0; 32];
+    secret.fill_with(|| fastrand::u8(..));
+    let secret = StaticSecret::from(secret);
+    let server_public = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(client_public));
+    let shared = Encoder::default().mpint(shared.as_bytes()).0;
+
+    let host_key_blob = key_blob(host_key);
+    let mut hash = Encoder::default()
+        .str(&client_version)
+        .str(server_version)
+        .string(&client_kexinit)
+        .string(&server_kexinit)
+        .string(&host_key_blob)
+        .string(&client_public)
+        .string(server_public.as_bytes())
+        .0;
+    hash.extend(&shared);
+    let hash = Sha256::digest(hash).to_vec();
+    let signature = host_key.sign(&hash);
+    let signature = Encoder::default()
+        .str("ssh-ed25519")
+        .string(&signature.to_bytes())
+        .0;
+    let reply = Encoder::default()
+        .byte(MSG_KEX_ECDH_REPLY)
+        .string(&host_key_blob)
+        .string(server_public.as_bytes())
+        .string(&signature)
+        .0;
+    sender.send(&reply)?;
+    sender.send(&[MSG_NEWKEYS])?;
+    assert_eq!(receive(&mut receiver)?, [MSG_NEWKEYS]);
+
+    // The first exchange's hash is also the session ID
+    let derive = |letter: u8| {
+        let mut input = shared.clone();
+        input.extend(&hash);
+        input.push(letter);
+        input.extend(&hash);
+        Sha256::digest(input).to_vec()
+    };
+    let keys = |iv: u8, key: u8, mac: u8| Keys {
+        cipher: Aes128Ctr::new_from_slices(&derive(key)[..16], &derive(iv)[..16]).unwrap(),
+        mac_key: derive(mac),
+    };
+    receiver.keys = Some(keys(b'A', b'C', b'E'));
+    sender.keys = Some(keys(b'B', b'D', b'F'));
+    Ok((Arc::new(Mutex::new(sender)), receiver))
+}
+
+/// Sends a channel's messages, which stop once it has been closed
+#[derive(Clone)]
+struct ChannelSender {
+    sender: Arc<Mutex<Sender>>,
+    peer: u32,
+    closed: Arc<AtomicBool>,
+}
+
+impl ChannelSender {
+    fn send(&self, message: Encoder) -> io::Result<()> {
+        let mut sender = self.sender.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        sender.send(&message.0)
+    }
+
+    fn message(&self, kind: u8) -> Encoder {
+        Encoder::default().byte(kind).u32(self.peer)
+    }
+
+    fn data(&self, data: &[u8], extended: bool) -> io::Result<()> {
+        for chunk in data.chunks(CHUNK) {
+            let message = match extended {
+                false => self.message(MSG_CHANNEL_DATA),
+                true => self.message(MSG_CHANNEL_EXTENDED_DATA).u32(1),
+            };
+            self.send(message.string(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.send(self.message(MSG_CHANNEL_EOF))?;
+        self.send(self.message(MSG_CHANNEL_CLOSE))?;
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Copies a stream to the channel until it ends
+    fn copy_from(&self, mut from: impl Read, extended: bool) -> io::Result<()> {
+        let mut buf = [0; CHUNK];
+        loop {
+            match from.read(&mut buf)? {
+                0 => return Ok(()),
+                n => self.data(&buf[..n], extended)?,
+            }
+        }
+    }
+}
+
+enum Channel {
+    Session {
+        sender: ChannelSender,
+        env: Vec<(String, String)>,
+        stdin: Option<ChildStdin>,
+    },
+    Tcp {
+        sender: ChannelSender,
+        stream: TcpStream,
+    },
+}
+
+impl Channel {
+    fn sender(&self) -> &ChannelSender {
+        match self {
+            Channel::Session { sender, .. } | Channel::Tcp { sender, .. } => sender,
+        }
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    host_key: &SigningKey,
+    forwarded: &Mutex<Vec<String>>,
+) -> io::Result<()> {
+    let (sender, mut receiver) = exchange_keys(stream, host_key)?;
+    let send = |message: Encoder| sender.lock().unwrap().send(&message.0);
+    let mut channels = HashMap::new();
+    let mut next_channel = 0;
+    loop {
+        let message = receive(&mut receiver)?;
+        let mut decoder = Decoder(&message);
+        match decoder.byte() {
+            MSG_SERVICE_REQUEST => {
+                let service = decoder.string();
+                send(Encoder::default().byte(MSG_SERVICE_ACCEPT).string(service))?;
+            }
+            MSG_USERAUTH_REQUEST => {
+                let username = decoder.str();
+                let _service = decoder.string();
+                let method = decoder.str();
+                let authenticated = method == "password" && {
+                    let _change = decoder.bool();
+                    username == USERNAME && decoder.str() == PASSWORD
+                };
+                send(match authenticated {
+                    true => Encoder::default().byte(MSG_USERAUTH_SUCCESS),
+                    false => Encoder::default()
+                        .byte(MSG_USERAUTH_FAILURE)
+                        .str("password")
+                        .bool(false),
+                })?;
+            }
+            MSG_GLOBAL_REQUEST => {
+                let _name = decoder.string();
+                if decoder.bool() {
+                    send(Encoder::default().byte(MSG_REQUEST_FAILURE))?;
+                }
+            }
+            MSG_CHANNEL_OPEN => {
+                let kind = decoder.str();
+                let peer = decoder.u32();
+                let channel_sender = ChannelSender {
+                    sender: sender.clone(),
+                    peer,
+                    closed: Arc::new(AtomicBool::new(false)),
+                };
+                let channel = match kind.as_str() {
+                    "session" => Channel::Session {
+                        sender: channel_sender,
+                        env: Vec::new(),
+                        stdin: None,
+                    },
+                    "direct-tcpip" => {
+                        let _window = decoder.u32();
+                        let _max_packet = decoder.u32();
+                        let address = format!("{}:{}", decoder.str(), decoder.u32());
+                        forwarded.lock().unwrap().push(address.clone());
+                        match TcpStream::connect(&address) {
+                            Ok(stream) => Channel::Tcp {
+                                sender: channel_sender,
+                                stream,
+                            },
+                            Err(e) => {
+                                send(
+                                    Encoder::default()
+                                        .byte(MSG_CHANNEL_OPEN_FAILURE)
+                                        .u32(peer)
+                                        .u32(2)
+                                        .str(&e.to_string())
+                                        .str(""),
+                                )?;
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {
+                        send(
+                            Encoder::default()
+                                .byte(MSG_CHANNEL_OPEN_FAILURE)
+                                .u32(peer)
+                                .u32(3)
+                                .str("Unknown channel type")
+                                .str(""),
+                        )?;
+                        continue;
+                    }
+                };
+                send(
+                    Encoder::default()
+                        .byte(MSG_CHANNEL_OPEN_CONFIRMATION)
+                        .u32(peer)
+                        .u32(next_channel)
+                        .u32(WINDOW)
+                        .u32(MAX_PACKET),
+                )?;
+                if let Channel::Tcp { sender, stream } = &channel {
+                    let (sender, stream) = (sender.clone(), stream.try_clone()?);
+                    std::thread::spawn(move || {
+                        let _ = sender.copy_from(stream, false);
+                        let _ = sender.close();
+                    });
+                }
+                channels.insert(next_channel, channel);
+                next_channel += 1;
+            }
+            MSG_CHANNEL_REQUEST => {
+                let id = decoder.u32();
+                let kind = decoder.str();
+                let want_reply = decoder.bool();
+                let Some(Channel::Session { sender, env, stdin }) = channels.get_mut(&id) else {
+                    continue;
+                };
+                let ok = match kind.as_str() {
+                    "pty-req" | "window-change" => true,
+                    "env" => {
+                        env.push((decoder.str(), decoder.str()));
+                        true
+                    }
+                    "exec" | "shell" => {
+                        let mut command = Command::new("sh");
+                        if kind == "exec" {
+                            command.arg("-c").arg(decoder.str());
+                        }
+                        let child = command
+                            .envs(env.iter().cloned())
+                            .stdin(Stdio::piped())
+                            .stdout(Stdio::piped())
+                            .stderr(Stdio::piped())
+                            .spawn();
+                        match child {
+                            Ok(mut child) => {
+                                *stdin = child.stdin.take();
+                                let sender = sender.clone();
+                                // Reply before any of the output is sent
+                                if want_reply {
+                                    sender.send(sender.message(MSG_CHANNEL_SUCCESS))?;
+                                }
+                                std::thread::spawn(move || {
+                                    let stderr = child.stderr.take().unwrap();
+                                    let errors = sender.clone();
+                                    let errors =
+                                        std::thread::spawn(move || errors.copy_from(stderr, true));
+                                    let _ = sender.copy_from(child.stdout.take().unwrap(), false);
+                                    let _ = errors.join();
+                                    let code = child.wait().unwrap().code().unwrap_or(255);
+                                    let status = sender
+                                        .message(MSG_CHANNEL_REQUEST)
+                                        .str("exit-status")
+                                        .bool(false)
+                                        .u32(code as u32);
+                                    let _ = sender.send(status);
+                                    let _ = sender.close();
+                                });
+                                continue;
+                            }
+                            Err(_) => false,
+                        }
+                    }
+                    _ => false,
+                };
+                if want_reply {
+                    let reply = match ok {
+                        true => MSG_CHANNEL_SUCCESS,
+                        false => MSG_CHANNEL_FAILURE,
+                    };
+                    sender.send(sender.message(reply))?;
+                }
+            }
+            MSG_CHANNEL_DATA => {
+                let id = decoder.u32();
+                let data = decoder.string();
+                let Some(channel) = channels.get_mut(&id) else {
+                    continue;
+                };
+                let written = match channel {
+                    Channel::Session {
+                        stdin: Some(stdin), ..
+                    } => stdin.write_all(data),
+                    Channel::Tcp { stream, .. } => stream.write_all(data),
+                    Channel::Session { stdin: None, .. } => Ok(()),
+                };
+                if written.is_ok() {
+                    let sender = channel.sender();
+                    let adjust = sender.message(MSG_CHANNEL_WINDOW_ADJUST);
+                    sender.send(adjust.u32(data.len() as u32))?;
+                }
+            }
+            MSG_CHANNEL_EOF => match channels.get_mut(&decoder.u32()) {
+                Some(Channel::Session { stdin, .. }) => *stdin = None,
+                Some(Channel::Tcp { stream, .. }) => {
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+                None => {}
+            },
+            MSG_CHANNEL_CLOSE => {
+                if let Some(channel) = channels.remove(&decoder.u32()) {
+                    if let Channel::Tcp { stream, .. } = &channel {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    let sender = channel.sender();
+                    sender.send(sender.message(MSG_CHANNEL_CLOSE))?;
+                    sender.closed.store(true, Ordering::SeqCst);
+                }
+            }
+            // Window adjustments, as the client never gets near the end of its window
+            _ => {}
+        }
+    }
+}